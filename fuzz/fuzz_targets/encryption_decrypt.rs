@@ -0,0 +1,23 @@
+#![no_main]
+
+use ascii_pay_nfc_terminal::nfc_module::nfc::mifare_desfire::Encryption;
+use libfuzzer_sys::fuzz_target;
+
+const KEY: [u8; 16] = [
+    0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF,
+];
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte selects the mode, the rest is the card response.
+    let (mode, data) = match data.split_first() {
+        Some((mode, data)) => (*mode, data),
+        None => return,
+    };
+
+    let encryption = match mode % 3 {
+        0 => Encryption::PlainText,
+        1 => Encryption::MACed(KEY.to_vec()),
+        _ => Encryption::Encrypted(KEY.to_vec()),
+    };
+    let _ = encryption.decrypt(data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use ascii_pay_nfc_terminal::nfc_module::nfc::mifare_desfire::Version;
+use ascii_pay_nfc_terminal::nfc_module::nfc::utils::Serializable;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Version::from_slice(data);
+});
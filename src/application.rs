@@ -1,76 +1,135 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
-use std::process::exit;
+use std::sync::Arc;
 
 use base64::engine::general_purpose;
 use base64::Engine;
 
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use tokio::sync::mpsc;
 
 use crate::{
-    nfc_module::NfcCommand,
-    websocket_server::{CardTypeDto, WebsocketRequestMessage, WebsocketResponseMessage},
+    nfc_module::{self, NfcCommand, NfcRequest},
+    websocket_server::{
+        negotiate_protocol_version, CapabilitiesDto, CardStatusDto, CardTypeDto, ModuleHealthDto,
+        ReaderStatusDto, Recipient, Role, SessionId, WebsocketMetrics, WebsocketRequest,
+        WebsocketRequestMessage, WebsocketResponse, WebsocketResponseMessage, PROTOCOL_VERSION,
+    },
+    ErrorCode, ServiceResult,
 };
 
+type WebsocketChannel = (Recipient, WebsocketResponse);
+
 enum ApplicationCommand {
-    Response(WebsocketResponseMessage),
-    Request(WebsocketRequestMessage),
-    Error { source: String, message: String },
+    Response(Recipient, WebsocketResponse),
+    Request(SessionId, Role, WebsocketRequest),
+    Error {
+        recipient: Recipient,
+        request_id: Option<String>,
+        reader: Option<String>,
+        code: ErrorCode,
+        source: String,
+        message: String,
+    },
+    QrScannerState(bool),
+    NfcReaders(Vec<String>),
+    NfcCard(String, Option<CardStatusDto>),
+    ModuleHealth(ModuleHealthDto),
+    /// A (re)started websocket server takes over all outgoing messages.
+    AttachWebsocket(mpsc::Sender<WebsocketChannel>),
+    /// A (re)started nfc module takes over all nfc requests.
+    AttachNfc(mpsc::Sender<NfcRequest>),
+}
+
+/// Sends a command to the application. If the application is gone the supervisor
+/// ends the process, so the message is only logged and dropped here.
+async fn send_command(sender: &mpsc::Sender<ApplicationCommand>, command: ApplicationCommand) {
+    if sender.send(command).await.is_err() {
+        error!("Internal message bus seems to be dead. Drop message!");
+    }
+}
+
+/// Registers a new receiver with the application, replacing the previous one.
+async fn attach<T>(
+    sender: &mpsc::Sender<ApplicationCommand>,
+    buffer: usize,
+    command: fn(mpsc::Sender<T>) -> ApplicationCommand,
+) -> ServiceResult<mpsc::Receiver<T>> {
+    let (tx, rx) = mpsc::channel(buffer);
+    sender.send(command(tx)).await?;
+    Ok(rx)
 }
 
 #[derive(Clone)]
 pub struct ApplicationResponseContext {
     sender: mpsc::Sender<ApplicationCommand>,
+    recipient: Recipient,
+    request_id: Option<String>,
+    reader: Option<String>,
 }
 
 impl ApplicationResponseContext {
-    pub async fn send_barcode_identify_request(&self, barcode: String) {
-        if self
-            .sender
-            .send(ApplicationCommand::Response(
-                WebsocketResponseMessage::BarcodeIdentifyRequest { barcode },
-            ))
-            .await
-            .is_err()
-        {
-            error!("Internal message bus seems to be dead. Aborting!");
-            exit(1);
+    /// Returns a context whose messages are only delivered to the given session
+    /// and carry the `request_id` of the request that caused them.
+    pub fn reply_to(&self, session: SessionId, request_id: Option<String>) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            recipient: Recipient::Session(session),
+            request_id,
+            reader: self.reader.clone(),
         }
     }
 
-    pub async fn send_nfc_identify_request(&self, card_id: Vec<u8>, name: String) {
-        if self
-            .sender
-            .send(ApplicationCommand::Response(
-                WebsocketResponseMessage::NfcIdentifyRequest {
-                    card_id: general_purpose::STANDARD.encode(card_id),
-                    name,
-                },
-            ))
-            .await
-            .is_err()
-        {
-            error!("Internal message bus seems to be dead. Aborting!");
-            exit(1);
+    /// Returns a context whose messages name the nfc reader they are about.
+    pub fn for_reader(&self, reader: &str) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            recipient: self.recipient,
+            request_id: self.request_id.clone(),
+            reader: Some(reader.to_owned()),
         }
     }
 
+    fn response(&self, message: WebsocketResponseMessage) -> ApplicationCommand {
+        ApplicationCommand::Response(
+            self.recipient,
+            WebsocketResponse {
+                request_id: self.request_id.clone(),
+                reader: self.reader.clone(),
+                message,
+            },
+        )
+    }
+
+    pub async fn send_barcode_identify_request(&self, barcode: String) {
+        send_command(
+            &self.sender,
+            self.response(WebsocketResponseMessage::BarcodeIdentifyRequest { barcode }),
+        )
+        .await;
+    }
+
+    pub async fn send_nfc_identify_request(&self, card_id: Vec<u8>, name: String) {
+        send_command(
+            &self.sender,
+            self.response(WebsocketResponseMessage::NfcIdentifyRequest {
+                card_id: general_purpose::STANDARD.encode(card_id),
+                name,
+            }),
+        )
+        .await;
+    }
+
     pub async fn send_nfc_challenge_request(&self, card_id: Vec<u8>, request: Vec<u8>) {
-        if self
-            .sender
-            .send(ApplicationCommand::Response(
-                WebsocketResponseMessage::NfcChallengeRequest {
-                    card_id: general_purpose::STANDARD.encode(card_id),
-                    request: general_purpose::STANDARD.encode(request),
-                },
-            ))
-            .await
-            .is_err()
-        {
-            error!("Internal message bus seems to be dead. Aborting!");
-            exit(1);
-        }
+        send_command(
+            &self.sender,
+            self.response(WebsocketResponseMessage::NfcChallengeRequest {
+                card_id: general_purpose::STANDARD.encode(card_id),
+                request: general_purpose::STANDARD.encode(request),
+            }),
+        )
+        .await;
     }
 
     pub async fn send_nfc_response_request(
@@ -79,35 +138,23 @@ impl ApplicationResponseContext {
         challenge: Vec<u8>,
         response: Vec<u8>,
     ) {
-        if self
-            .sender
-            .send(ApplicationCommand::Response(
-                WebsocketResponseMessage::NfcResponseRequest {
-                    card_id: general_purpose::STANDARD.encode(card_id),
-                    challenge: general_purpose::STANDARD.encode(challenge),
-                    response: general_purpose::STANDARD.encode(response),
-                },
-            ))
-            .await
-            .is_err()
-        {
-            error!("Internal message bus seems to be dead. Aborting!");
-            exit(1);
-        }
+        send_command(
+            &self.sender,
+            self.response(WebsocketResponseMessage::NfcResponseRequest {
+                card_id: general_purpose::STANDARD.encode(card_id),
+                challenge: general_purpose::STANDARD.encode(challenge),
+                response: general_purpose::STANDARD.encode(response),
+            }),
+        )
+        .await;
     }
 
     pub async fn send_nfc_card_removed(&self) {
-        if self
-            .sender
-            .send(ApplicationCommand::Response(
-                WebsocketResponseMessage::NfcCardRemoved,
-            ))
-            .await
-            .is_err()
-        {
-            error!("Internal message bus seems to be dead. Aborting!");
-            exit(1);
-        }
+        send_command(
+            &self.sender,
+            self.response(WebsocketResponseMessage::NfcCardRemoved),
+        )
+        .await;
     }
 
     pub async fn send_nfc_register_request(
@@ -117,37 +164,64 @@ impl ApplicationResponseContext {
         card_type: CardTypeDto,
         data: Option<Vec<u8>>,
     ) {
-        if self
-            .sender
-            .send(ApplicationCommand::Response(
-                WebsocketResponseMessage::NfcRegisterRequest {
-                    name,
-                    card_id: general_purpose::STANDARD.encode(card_id),
-                    card_type,
-                    data: data.map(|d| general_purpose::STANDARD.encode(d)),
-                },
-            ))
-            .await
-            .is_err()
-        {
-            error!("Internal message bus seems to be dead. Aborting!");
-            exit(1);
-        }
+        send_command(
+            &self.sender,
+            self.response(WebsocketResponseMessage::NfcRegisterRequest {
+                name,
+                card_id: general_purpose::STANDARD.encode(card_id),
+                card_type,
+                data: data.map(|d| general_purpose::STANDARD.encode(d)),
+            }),
+        )
+        .await;
     }
 
-    pub async fn send_error<S: Into<String>, M: Into<String>>(&self, source: S, message: M) {
-        if self
-            .sender
-            .send(ApplicationCommand::Error {
+    pub async fn send_error<S: Into<String>, M: Into<String>>(
+        &self,
+        code: ErrorCode,
+        source: S,
+        message: M,
+    ) {
+        send_command(
+            &self.sender,
+            ApplicationCommand::Error {
+                recipient: self.recipient,
+                request_id: self.request_id.clone(),
+                reader: self.reader.clone(),
+                code,
                 source: source.into(),
                 message: message.into(),
-            })
-            .await
-            .is_err()
-        {
-            error!("Internal message bus seems to be dead. Aborting!");
-            exit(1);
-        }
+            },
+        )
+        .await;
+    }
+
+    pub async fn send_qr_scanner_state(&self, connected: bool) {
+        send_command(&self.sender, ApplicationCommand::QrScannerState(connected)).await;
+    }
+
+    /// Reports the names of all connected nfc readers.
+    pub async fn send_nfc_readers(&self, readers: Vec<String>) {
+        send_command(&self.sender, ApplicationCommand::NfcReaders(readers)).await;
+    }
+
+    /// Reports the card currently held by a nfc reader.
+    pub async fn send_nfc_card(&self, reader: &str, card: Option<CardStatusDto>) {
+        send_command(
+            &self.sender,
+            ApplicationCommand::NfcCard(reader.to_owned(), card),
+        )
+        .await;
+    }
+
+    /// Reports the health of a supervised module.
+    pub async fn send_module_health(&self, health: ModuleHealthDto) {
+        send_command(&self.sender, ApplicationCommand::ModuleHealth(health)).await;
+    }
+
+    /// Returns the receiver for all nfc requests from now on.
+    pub async fn attach_nfc(&self) -> ServiceResult<mpsc::Receiver<NfcRequest>> {
+        attach(&self.sender, 4, ApplicationCommand::AttachNfc).await
     }
 }
 
@@ -157,31 +231,44 @@ pub struct ApplicationRequestContext {
 }
 
 impl ApplicationRequestContext {
-    pub async fn send_websocket_request(&self, message: WebsocketRequestMessage) {
-        if self
-            .sender
-            .send(ApplicationCommand::Request(message))
-            .await
-            .is_err()
-        {
-            error!("Internal message bus seems to be dead. Aborting!");
-            exit(1);
-        }
+    pub async fn send_websocket_request(
+        &self,
+        session: SessionId,
+        role: Role,
+        request: WebsocketRequest,
+    ) {
+        send_command(
+            &self.sender,
+            ApplicationCommand::Request(session, role, request),
+        )
+        .await;
     }
 
-    pub async fn error<S: Into<String>, M: Into<String>>(&self, source: S, message: M) {
-        if self
-            .sender
-            .send(ApplicationCommand::Error {
+    pub async fn error<S: Into<String>, M: Into<String>>(
+        &self,
+        session: SessionId,
+        request_id: Option<String>,
+        code: ErrorCode,
+        source: S,
+        message: M,
+    ) {
+        send_command(
+            &self.sender,
+            ApplicationCommand::Error {
+                recipient: Recipient::Session(session),
+                request_id,
+                reader: None,
+                code,
                 source: source.into(),
                 message: message.into(),
-            })
-            .await
-            .is_err()
-        {
-            error!("Internal message bus seems to be dead. Aborting!");
-            exit(1);
-        }
+            },
+        )
+        .await;
+    }
+
+    /// Returns the receiver for all outgoing websocket messages from now on.
+    pub async fn attach_websocket(&self) -> ServiceResult<mpsc::Receiver<WebsocketChannel>> {
+        attach(&self.sender, 4, ApplicationCommand::AttachWebsocket).await
     }
 }
 
@@ -195,12 +282,18 @@ fn read_file_to_vec(filename: &str) -> Vec<u8> {
 pub struct Application {
     command_sender: mpsc::Sender<ApplicationCommand>,
     command_recv: mpsc::Receiver<ApplicationCommand>,
-    websocket_sender: Option<mpsc::Sender<WebsocketResponseMessage>>,
-    nfc_sender: Option<mpsc::Sender<NfcCommand>>,
+    websocket_sender: Option<mpsc::Sender<WebsocketChannel>>,
+    nfc_sender: Option<mpsc::Sender<NfcRequest>>,
+    simulation: bool,
+    qr_scanner_connected: bool,
+    nfc_readers: Vec<String>,
+    nfc_cards: HashMap<String, CardStatusDto>,
+    modules: Vec<ModuleHealthDto>,
+    websocket_metrics: Arc<WebsocketMetrics>,
 }
 
 impl Application {
-    pub fn new() -> Self {
+    pub fn new(simulation: bool) -> Self {
         let (tx, rx) = mpsc::channel(32);
 
         Self {
@@ -208,6 +301,12 @@ impl Application {
             command_recv: rx,
             websocket_sender: None,
             nfc_sender: None,
+            simulation,
+            qr_scanner_connected: false,
+            nfc_readers: Vec::new(),
+            nfc_cards: HashMap::new(),
+            modules: Vec::new(),
+            websocket_metrics: Arc::new(WebsocketMetrics::default()),
         }
     }
 
@@ -220,30 +319,284 @@ impl Application {
     pub fn get_response_context(&self) -> ApplicationResponseContext {
         ApplicationResponseContext {
             sender: self.command_sender.clone(),
+            recipient: Recipient::Broadcast,
+            request_id: None,
+            reader: None,
         }
     }
 
-    pub fn get_websocket_receiver(&mut self) -> mpsc::Receiver<WebsocketResponseMessage> {
-        let (tx, rx) = mpsc::channel(4);
-        self.websocket_sender = Some(tx);
-        rx
+    pub fn get_websocket_metrics(&self) -> Arc<WebsocketMetrics> {
+        self.websocket_metrics.clone()
     }
 
-    pub fn get_nfc_receiver(&mut self) -> mpsc::Receiver<NfcCommand> {
-        let (tx, rx) = mpsc::channel(4);
-        self.nfc_sender = Some(tx);
-        rx
-    }
-
-    fn parse_base64(value: String, parameter: &str) -> Result<Vec<u8>, (String, String)> {
+    fn parse_base64(
+        value: String,
+        parameter: &str,
+    ) -> Result<Vec<u8>, (ErrorCode, String, String)> {
         general_purpose::STANDARD.decode(value).map_err(|_| {
             (
+                ErrorCode::Base64DecodeError,
                 "Base64 decode error".into(),
                 format!("Could not decode base64 parameter '{parameter}'."),
             )
         })
     }
 
+    async fn send_to_websocket(
+        &self,
+        recipient: Recipient,
+        request_id: Option<String>,
+        message: WebsocketResponseMessage,
+    ) {
+        self.send_response(
+            recipient,
+            WebsocketResponse {
+                request_id,
+                reader: None,
+                message,
+            },
+        )
+        .await;
+    }
+
+    async fn send_response(&self, recipient: Recipient, response: WebsocketResponse) {
+        if let Some(sender) = self.websocket_sender.as_ref() {
+            if sender.send((recipient, response)).await.is_err() {
+                debug!("Websocket server is not running. Drop message!");
+            }
+        }
+    }
+
+    async fn send_to_nfc(&self, request: NfcRequest) {
+        let result = match self.nfc_sender.as_ref() {
+            Some(sender) => sender.send(request).await.map_err(|e| e.0),
+            None => Err(request),
+        };
+
+        if let Err(request) = result {
+            warn!("NFC module is not running. Reject request!");
+            self.send_response(
+                Recipient::Session(request.session),
+                WebsocketResponse {
+                    request_id: request.request_id,
+                    reader: request.reader,
+                    message: WebsocketResponseMessage::Error {
+                        code: ErrorCode::Unavailable,
+                        source: "NFC Reader".into(),
+                        message: "NFC module is not running.".into(),
+                    },
+                },
+            )
+            .await;
+        }
+    }
+
+    async fn send_welcome(
+        &self,
+        session: SessionId,
+        role: Role,
+        request_id: Option<String>,
+        protocol_versions: Vec<u32>,
+    ) {
+        // Incompatible clients are already rejected by the websocket server.
+        let protocol_version =
+            negotiate_protocol_version(&protocol_versions).unwrap_or(PROTOCOL_VERSION);
+
+        let build = format!(
+            "{}-{} ({})",
+            std::env::consts::ARCH,
+            std::env::consts::OS,
+            if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            }
+        );
+
+        self.send_to_websocket(
+            Recipient::Session(session),
+            request_id,
+            WebsocketResponseMessage::Welcome {
+                protocol_version,
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                build,
+                role,
+                capabilities: CapabilitiesDto {
+                    card_handlers: nfc_module::card_handlers(),
+                    qr_scanner: self.qr_scanner_connected,
+                    simulation: self.simulation,
+                },
+            },
+        )
+        .await;
+    }
+
+    fn reader_status(&self) -> Vec<ReaderStatusDto> {
+        self.nfc_readers
+            .iter()
+            .map(|name| ReaderStatusDto {
+                name: name.clone(),
+                card: self.nfc_cards.get(name).cloned(),
+            })
+            .collect()
+    }
+
+    /// Broadcasts which readers were plugged in or out since the last report.
+    async fn update_nfc_readers(&mut self, readers: Vec<String>) {
+        let disconnected = self.nfc_readers.iter().filter(|r| !readers.contains(r));
+        let connected = readers.iter().filter(|r| !self.nfc_readers.contains(r));
+        let events = disconnected
+            .map(|r| (r.clone(), WebsocketResponseMessage::ReaderDisconnected))
+            .chain(connected.map(|r| (r.clone(), WebsocketResponseMessage::ReaderConnected)))
+            .collect::<Vec<_>>();
+
+        self.nfc_readers = readers;
+        let readers = &self.nfc_readers;
+        self.nfc_cards.retain(|reader, _| readers.contains(reader));
+        for (reader, message) in events {
+            info!("{:?}: {}", message, reader);
+            self.send_response(
+                Recipient::Broadcast,
+                WebsocketResponse {
+                    request_id: None,
+                    reader: Some(reader),
+                    message,
+                },
+            )
+            .await;
+        }
+    }
+
+    async fn send_status(&self, session: SessionId, request_id: Option<String>) {
+        self.send_to_websocket(
+            Recipient::Session(session),
+            request_id,
+            WebsocketResponseMessage::Status {
+                readers: self.reader_status(),
+                qr_scanner: self.qr_scanner_connected,
+                simulation: self.simulation,
+                websocket: self.websocket_metrics.snapshot(),
+                modules: self.modules.clone(),
+            },
+        )
+        .await;
+    }
+
+    async fn handle_request(&self, session: SessionId, role: Role, request: WebsocketRequest) {
+        let request_id = request.request_id;
+        let reader = request.reader;
+
+        if role < request.message.required_role() {
+            warn!(
+                "Session {} with role {:?} is not allowed to send {}",
+                session,
+                role,
+                request.message.name()
+            );
+            self.send_to_websocket(
+                Recipient::Session(session),
+                request_id,
+                WebsocketResponseMessage::Error {
+                    code: ErrorCode::PermissionDenied,
+                    source: "WebSocket".to_owned(),
+                    message: format!(
+                        "Role {:?} is not allowed to send {}!",
+                        role,
+                        request.message.name()
+                    ),
+                },
+            )
+            .await;
+            return;
+        }
+
+        let nfc_command = match request.message {
+            WebsocketRequestMessage::Hello { protocol_versions } => {
+                self.send_welcome(session, role, request_id, protocol_versions)
+                    .await;
+                return;
+            }
+            WebsocketRequestMessage::GetStatus => {
+                self.send_status(session, request_id).await;
+                return;
+            }
+            WebsocketRequestMessage::GetReaders => {
+                self.send_to_websocket(
+                    Recipient::Session(session),
+                    request_id,
+                    WebsocketResponseMessage::Readers {
+                        readers: self.reader_status(),
+                    },
+                )
+                .await;
+                return;
+            }
+            WebsocketRequestMessage::NfcIdentifyResponse { card_id, card_type } => {
+                match Self::parse_base64(card_id, "card_id") {
+                    Ok(card_id) => Ok(NfcCommand::IdentifyResponse { card_id, card_type }),
+                    Err(err) => Err(err),
+                }
+            }
+            WebsocketRequestMessage::NfcChallengeResponse { card_id, challenge } => {
+                match Self::parse_base64(card_id, "card_id") {
+                    Ok(card_id) => match Self::parse_base64(challenge, "challenge") {
+                        Ok(challenge) => Ok(NfcCommand::ChallengeResponse { card_id, challenge }),
+                        Err(err) => Err(err),
+                    },
+                    Err(err) => Err(err),
+                }
+            }
+            WebsocketRequestMessage::NfcResponseResponse {
+                card_id,
+                session_key,
+            } => match Self::parse_base64(card_id, "card_id") {
+                Ok(card_id) => match Self::parse_base64(session_key, "session_key") {
+                    Ok(session_key) => Ok(NfcCommand::ResponseResponse {
+                        card_id,
+                        session_key,
+                    }),
+                    Err(err) => Err(err),
+                },
+                Err(err) => Err(err),
+            },
+            WebsocketRequestMessage::NfcRegister { card_id } => {
+                match Self::parse_base64(card_id, "card_id") {
+                    Ok(card_id) => Ok(NfcCommand::Register { card_id }),
+                    Err(err) => Err(err),
+                }
+            }
+            WebsocketRequestMessage::NfcReauthenticate => Ok(NfcCommand::Reauthenticate),
+        };
+
+        match nfc_command {
+            Ok(command) => {
+                self.send_to_nfc(NfcRequest {
+                    session,
+                    request_id,
+                    reader,
+                    command,
+                })
+                .await;
+            }
+            Err((code, source, message)) => {
+                warn!("Error({:?}, {:?}, {:?})", code, source, message);
+                self.send_response(
+                    Recipient::Session(session),
+                    WebsocketResponse {
+                        request_id,
+                        reader,
+                        message: WebsocketResponseMessage::Error {
+                            code,
+                            source,
+                            message,
+                        },
+                    },
+                )
+                .await;
+            }
+        }
+    }
+
     pub async fn run(mut self) {
         info!("Start application module");
 
@@ -251,106 +604,67 @@ impl Application {
             let recv = self.command_recv.recv().await;
             if let Some(command) = recv {
                 match command {
-                    ApplicationCommand::Request(request) => {
-                        let nfc_command = match request {
-                            WebsocketRequestMessage::NfcIdentifyResponse { card_id, card_type } => {
-                                match Self::parse_base64(card_id, "card_id") {
-                                    Ok(card_id) => {
-                                        Ok(NfcCommand::IdentifyResponse { card_id, card_type })
-                                    }
-                                    Err(err) => Err(err),
-                                }
-                            }
-                            WebsocketRequestMessage::NfcChallengeResponse {
-                                card_id,
-                                challenge,
-                            } => match Self::parse_base64(card_id, "card_id") {
-                                Ok(card_id) => match Self::parse_base64(challenge, "challenge") {
-                                    Ok(challenge) => {
-                                        Ok(NfcCommand::ChallengeResponse { card_id, challenge })
-                                    }
-                                    Err(err) => Err(err),
-                                },
-                                Err(err) => Err(err),
-                            },
-                            WebsocketRequestMessage::NfcResponseResponse {
-                                card_id,
-                                session_key,
-                            } => match Self::parse_base64(card_id, "card_id") {
-                                Ok(card_id) => match Self::parse_base64(session_key, "session_key")
-                                {
-                                    Ok(session_key) => Ok(NfcCommand::ResponseResponse {
-                                        card_id,
-                                        session_key,
-                                    }),
-                                    Err(err) => Err(err),
+                    ApplicationCommand::Request(session, role, request) => {
+                        self.handle_request(session, role, request).await;
+                    }
+                    ApplicationCommand::Response(recipient, response) => {
+                        self.send_response(recipient, response).await;
+                    }
+                    ApplicationCommand::Error {
+                        recipient,
+                        request_id,
+                        reader,
+                        code,
+                        source,
+                        message,
+                    } => {
+                        warn!("Error({:?}, {:?}, {:?})", code, source, message);
+                        self.send_response(
+                            recipient,
+                            WebsocketResponse {
+                                request_id,
+                                reader,
+                                message: WebsocketResponseMessage::Error {
+                                    code,
+                                    source,
+                                    message,
                                 },
-                                Err(err) => Err(err),
                             },
-                            WebsocketRequestMessage::NfcRegister { card_id } => {
-                                match Self::parse_base64(card_id, "card_id") {
-                                    Ok(card_id) => Ok(NfcCommand::Register { card_id }),
-                                    Err(err) => Err(err),
-                                }
-                            }
-                            WebsocketRequestMessage::NfcReauthenticate => {
-                                Ok(NfcCommand::Reauthenticate)
-                            }
-                        };
-
-                        match nfc_command {
-                            Ok(nfc_command) => {
-                                if let Some(sender) = self.nfc_sender.as_ref() {
-                                    if sender.send(nfc_command).await.is_err() {
-                                        error!("Internal message bus seems to be dead. Aborting!");
-                                        exit(1);
-                                    }
-                                }
-                            }
-                            Err((source, message)) => {
-                                warn!("Error({:?}, {:?})", source, message);
-                                if let Some(sender) = self.websocket_sender.as_ref() {
-                                    if sender
-                                        .send(WebsocketResponseMessage::Error { source, message })
-                                        .await
-                                        .is_err()
-                                    {
-                                        error!("Internal message bus seems to be dead. Aborting!");
-                                        exit(1);
-                                    }
-                                }
-                            }
-                        }
+                        )
+                        .await;
                     }
-                    ApplicationCommand::Response(response) => {
-                        if let Some(sender) = self.websocket_sender.as_ref() {
-                            if sender.send(response).await.is_err() {
-                                error!("Internal message bus seems to be dead. Aborting!");
-                                exit(1);
-                            }
-                        }
+                    ApplicationCommand::QrScannerState(connected) => {
+                        self.qr_scanner_connected = connected;
+                    }
+                    ApplicationCommand::NfcReaders(readers) => {
+                        self.update_nfc_readers(readers).await;
                     }
-                    ApplicationCommand::Error { source, message } => {
-                        warn!("Error({:?}, {:?})", source, message);
-                        if let Some(sender) = self.websocket_sender.as_ref() {
-                            if sender
-                                .send(WebsocketResponseMessage::Error { source, message })
-                                .await
-                                .is_err()
-                            {
-                                error!("Internal message bus seems to be dead. Aborting!");
-                                exit(1);
-                            }
+                    ApplicationCommand::NfcCard(reader, Some(card)) => {
+                        self.nfc_cards.insert(reader, card);
+                    }
+                    ApplicationCommand::NfcCard(reader, None) => {
+                        self.nfc_cards.remove(&reader);
+                    }
+                    ApplicationCommand::ModuleHealth(health) => {
+                        match self.modules.iter_mut().find(|m| m.module == health.module) {
+                            Some(module) => *module = health.clone(),
+                            None => self.modules.push(health.clone()),
                         }
+                        self.send_to_websocket(
+                            Recipient::Broadcast,
+                            None,
+                            WebsocketResponseMessage::ModuleHealth(health),
+                        )
+                        .await;
+                    }
+                    ApplicationCommand::AttachWebsocket(sender) => {
+                        self.websocket_sender = Some(sender);
+                    }
+                    ApplicationCommand::AttachNfc(sender) => {
+                        self.nfc_sender = Some(sender);
                     }
                 }
             }
         }
     }
 }
-
-impl Default for Application {
-    fn default() -> Self {
-        Self::new()
-    }
-}
@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use log::warn;
+
+/// Reads a duration in whole seconds from the environment variable `name`.
+///
+/// Falls back to `default` seconds if the variable is unset or not a number,
+/// the latter is logged.
+pub fn env_seconds(name: &str, default: u64) -> Duration {
+    let seconds = match std::env::var(name) {
+        Ok(value) => value.trim().parse().unwrap_or_else(|_| {
+            warn!(
+                "Invalid {} '{}', use {} seconds instead!",
+                name, value, default
+            );
+            default
+        }),
+        Err(_) => default,
+    };
+    Duration::from_secs(seconds)
+}
+
+#[test]
+pub fn env_seconds_test() {
+    let name = "ASCII_PAY_ENV_SECONDS_TEST";
+
+    assert_eq!(env_seconds(name, 5), Duration::from_secs(5));
+    std::env::set_var(name, " 12 ");
+    assert_eq!(env_seconds(name, 5), Duration::from_secs(12));
+    std::env::set_var(name, "12s");
+    assert_eq!(env_seconds(name, 5), Duration::from_secs(5));
+    std::env::remove_var(name);
+}
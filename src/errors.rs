@@ -1,3 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+use crate::nfc_module::nfc::{NfcError, NfcErrorKind};
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum RemoteErrorType {
     NotFound,
@@ -21,11 +25,73 @@ pub enum ServiceError {
     NotFound,
 
     Unauthorized,
+
+    NfcError(NfcError),
+}
+
+/// Stable, machine-readable error code, sent next to the human-readable text.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum ErrorCode {
+    NoCard,
+    UnsupportedCard,
+    PermissionDenied,
+    IntegrityError,
+    CommunicationError,
+    Base64DecodeError,
+    ParseError,
+    Timeout,
+    UnexpectedCommand,
+    CardMismatch,
+    BadRequest,
+    UnsupportedProtocolVersion,
+    NotFound,
+    Unavailable,
+    InternalError,
+}
+
+impl From<RemoteErrorType> for ErrorCode {
+    fn from(error: RemoteErrorType) -> Self {
+        match error {
+            RemoteErrorType::NotFound => ErrorCode::NotFound,
+            RemoteErrorType::BadRequest => ErrorCode::BadRequest,
+            RemoteErrorType::Unauthorized => ErrorCode::PermissionDenied,
+            RemoteErrorType::Internal => ErrorCode::InternalError,
+            RemoteErrorType::Unavailable => ErrorCode::Unavailable,
+        }
+    }
+}
+
+impl From<&NfcError> for ErrorCode {
+    fn from(error: &NfcError) -> Self {
+        match error.kind {
+            NfcErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            NfcErrorKind::CommunicationError => ErrorCode::CommunicationError,
+            NfcErrorKind::ByteParseError => ErrorCode::ParseError,
+            NfcErrorKind::IntegrityError => ErrorCode::IntegrityError,
+            NfcErrorKind::UnknownError => ErrorCode::InternalError,
+        }
+    }
+}
+
+impl ServiceError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ServiceError::BadRequest(_, _) => ErrorCode::BadRequest,
+            ServiceError::InternalError(_, _) => ErrorCode::InternalError,
+            ServiceError::RemoteError(error, _) => (*error).into(),
+            ServiceError::NotFound => ErrorCode::NotFound,
+            ServiceError::Unauthorized => ErrorCode::PermissionDenied,
+            ServiceError::NfcError(error) => error.into(),
+        }
+    }
 }
 
 impl std::fmt::Display for ServiceError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self:?}")
+        match self {
+            ServiceError::NfcError(error) => write!(f, "NfcError({error})"),
+            _ => write!(f, "{self:?}"),
+        }
     }
 }
 
@@ -74,6 +140,12 @@ impl From<tokio_tungstenite::tungstenite::Error> for ServiceError {
     }
 }
 
+impl From<tokio_rustls::rustls::Error> for ServiceError {
+    fn from(error: tokio_rustls::rustls::Error) -> Self {
+        ServiceError::InternalError("TLS error", format!("{error}"))
+    }
+}
+
 impl<T> From<tokio::sync::mpsc::error::SendError<T>> for ServiceError {
     fn from(error: tokio::sync::mpsc::error::SendError<T>) -> Self {
         ServiceError::InternalError("Internal communication error", format!("{error}"))
@@ -82,13 +154,25 @@ impl<T> From<tokio::sync::mpsc::error::SendError<T>> for ServiceError {
 
 impl From<tokio::task::JoinError> for ServiceError {
     fn from(error: tokio::task::JoinError) -> Self {
-        ServiceError::InternalError("Tokio join error", format!("{error}"))
+        if !error.is_panic() {
+            return ServiceError::InternalError("Tokio join error", format!("{error}"));
+        }
+
+        let panic = error.into_panic();
+        let message = match panic.downcast_ref::<&str>() {
+            Some(message) => (*message).to_owned(),
+            None => panic
+                .downcast_ref::<String>()
+                .cloned()
+                .unwrap_or_else(|| "Unknown panic".to_owned()),
+        };
+        ServiceError::InternalError("Task panicked", message)
     }
 }
 
-impl From<crate::nfc_module::nfc::NfcError> for ServiceError {
-    fn from(error: crate::nfc_module::nfc::NfcError) -> Self {
-        ServiceError::InternalError("NFC error", format!("{error:?}"))
+impl From<NfcError> for ServiceError {
+    fn from(error: NfcError) -> Self {
+        ServiceError::NfcError(error)
     }
 }
 
@@ -0,0 +1,17 @@
+#![allow(dead_code)]
+#![allow(unused_variables)]
+#![allow(non_snake_case)]
+
+#[macro_use]
+extern crate hex_literal;
+
+pub mod application;
+pub mod config;
+
+mod errors;
+pub use errors::*;
+
+pub mod nfc_module;
+pub mod qr_module;
+pub mod supervisor;
+pub mod websocket_server;
@@ -1,27 +1,17 @@
-#![allow(dead_code)]
-#![allow(unused_variables)]
 #![allow(non_snake_case)]
 
-#[macro_use]
-extern crate hex_literal;
-
-pub mod application;
-
-mod errors;
+use ascii_pay_nfc_terminal::{
+    application::Application,
+    nfc_module::NfcModule,
+    qr_module::QrModule,
+    supervisor::{self, Supervisor},
+    websocket_server::WebsocketServer,
+    ServiceError,
+};
 use env_logger::Env;
-pub use errors::*;
-
-mod websocket_server;
-use websocket_server::WebsocketServer;
-pub mod nfc_module;
-use nfc_module::NfcModule;
-mod qr_module;
-use qr_module::QrModule;
-
 use std::{env, process::exit};
 
-use application::Application;
-use log::error;
+use log::{error, info};
 use tokio::signal;
 
 #[tokio::main(worker_threads = 4)]
@@ -36,37 +26,63 @@ async fn main() {
     let args: Vec<String> = env::args().collect();
     let useSimulation = args.iter().any(|a| a == "--simulate");
 
-    let default_panic = std::panic::take_hook();
-    std::panic::set_hook(Box::new(move |info| {
-        default_panic(info);
-        std::process::exit(1);
-    }));
+    let application = Application::new(useSimulation);
+    let mut supervisor = Supervisor::new(application.get_response_context());
 
-    let mut application = Application::new();
+    let request_context = application.get_request_context();
+    let websocket_metrics = application.get_websocket_metrics();
+    supervisor.spawn("websocket", move |shutdown| {
+        WebsocketServer::new(request_context.clone(), websocket_metrics.clone()).run(shutdown)
+    });
 
-    let websocket_server = WebsocketServer::new(
-        application.get_request_context(),
-        application.get_websocket_receiver(),
-    );
-    tokio::spawn(websocket_server.run());
-
-    let qr_module = QrModule::new(application.get_response_context());
-    tokio::spawn(qr_module.run(useSimulation));
+    let qr_context = application.get_response_context();
+    supervisor.spawn("qr", move |shutdown| {
+        QrModule::new(qr_context.clone()).run(useSimulation, shutdown)
+    });
 
-    let nfc_module = NfcModule::new(
-        application.get_response_context(),
-        application.get_nfc_receiver(),
-    );
-    tokio::spawn(nfc_module.run(useSimulation));
+    let nfc_context = application.get_response_context();
+    supervisor.spawn("nfc", move |shutdown| {
+        NfcModule::new(nfc_context.clone()).run(useSimulation, shutdown)
+    });
 
-    tokio::spawn(application.run());
-    match signal::ctrl_c().await {
-        Ok(()) => {
-            exit(0);
-        }
-        Err(err) => {
-            error!("Unable to listen for shutdown signal: {}", err);
+    // Without the application there is no message bus left to recover with.
+    let application = tokio::spawn(application.run());
+    tokio::select! {
+        result = application => {
+            match result {
+                Ok(()) => error!("Application module stopped unexpectedly!"),
+                Err(e) => error!("Application module failed: {}", ServiceError::from(e)),
+            }
             exit(1);
         }
+        result = shutdown_signal() => match result {
+            Ok(signal) => {
+                info!("Received {}, shutting down", signal);
+                let timeout = supervisor::shutdown_timeout_from_env();
+                if supervisor.shutdown(timeout).await {
+                    exit(0);
+                }
+                exit(1);
+            }
+            Err(err) => {
+                error!("Unable to listen for shutdown signal: {}", err);
+                exit(1);
+            }
+        }
     }
 }
+
+/// Waits for Ctrl-C or SIGTERM, which is what systemd sends on stop.
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result.map(|_| "SIGINT"),
+            _ = terminate.recv() => Ok("SIGTERM"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    signal::ctrl_c().await.map(|_| "Ctrl-C")
+}
@@ -1,68 +1,14 @@
-use aes::Aes256;
-use block_modes::block_padding::ZeroPadding;
-use block_modes::cipher::{BlockCipher, BlockDecrypt, BlockEncrypt, NewBlockCipher};
-use block_modes::{BlockMode, Cbc};
-use generic_array::GenericArray;
 use log::info;
 use rand::RngCore;
 
 use crate::ServiceError;
 use crate::{application::ApplicationResponseContext, ServiceResult};
 
-use super::nfc::NfcCard;
+use super::nfc::mifare_utils::{aes_decrypt, aes_encrypt};
+use super::nfc::{CardStatus, NfcCard, NfcError, NfcErrorKind};
 
 pub const MIFARE_CLASSIC_ID_REQUEST: [u8; 5] = hex!("FF CA 00 00 00");
 
-/// Communication to the mifare desfire always requires the tdes decribt
-struct NfcAes {
-    cipher: Aes256,
-}
-
-impl NewBlockCipher for NfcAes {
-    type KeySize = <Aes256 as NewBlockCipher>::KeySize;
-
-    fn new(key: &GenericArray<u8, Self::KeySize>) -> Self {
-        NfcAes {
-            cipher: Aes256::new(key),
-        }
-    }
-}
-
-impl BlockCipher for NfcAes {
-    type BlockSize = <Aes256 as BlockCipher>::BlockSize;
-    type ParBlocks = <Aes256 as BlockCipher>::ParBlocks;
-}
-
-impl BlockEncrypt for NfcAes {
-    fn encrypt_block(&self, block: &mut GenericArray<u8, Self::BlockSize>) {
-        self.cipher.encrypt_block(block)
-    }
-}
-
-impl BlockDecrypt for NfcAes {
-    fn decrypt_block(&self, block: &mut GenericArray<u8, Self::BlockSize>) {
-        self.cipher.decrypt_block(block)
-    }
-}
-
-fn aes_encrypt(key: &[u8], value: &[u8]) -> ServiceResult<Vec<u8>> {
-    let key = GenericArray::from_slice(key);
-
-    let iv = GenericArray::from_slice(&[0u8; 16]);
-    let cipher: Cbc<NfcAes, ZeroPadding> = Cbc::new(NfcAes::new(key), iv);
-
-    Ok(cipher.encrypt_vec(value))
-}
-
-fn aes_decrypt(key: &[u8], value: &[u8]) -> ServiceResult<Vec<u8>> {
-    let key = GenericArray::from_slice(key);
-
-    let iv = GenericArray::from_slice(&[0u8; 16]);
-    let cipher: Cbc<NfcAes, ZeroPadding> = Cbc::new(NfcAes::new(key), iv);
-
-    Ok(cipher.decrypt_vec(value)?)
-}
-
 fn generate_key() -> [u8; 32] {
     let mut data = [0u8; 32];
     rand::thread_rng().fill_bytes(&mut data);
@@ -99,6 +45,8 @@ pub struct GenericNfcHandler {
 }
 
 impl GenericNfcHandler {
+    pub const NAME: &'static str = "MiFareClassic";
+
     fn get_card_id(&mut self) -> ServiceResult<Vec<u8>> {
         if let Some(id) = self.card.get_id() {
             return Ok(id);
@@ -107,6 +55,16 @@ impl GenericNfcHandler {
         let atr = self.card.get_atr()?;
         let id = self.card.transmit(&MIFARE_CLASSIC_ID_REQUEST)?;
 
+        // A failed request only returns the status words, e.g. `6A 81`.
+        if let [sw1, sw2] = id[..] {
+            if [sw1, sw2] != [0x90, 0x00] {
+                return Err(NfcError::new(NfcErrorKind::CommunicationError)
+                    .with_command("get_uid")
+                    .with_status(CardStatus::Words(sw1, sw2))
+                    .into());
+            }
+        }
+
         let mut card_id = Vec::<u8>::with_capacity(atr.len() + id.len());
         card_id.extend(&atr);
         card_id.extend(&id);
@@ -201,9 +159,14 @@ impl GenericNfcHandler {
         let card_id = self.get_card_id()?;
         let key = get_reader_key();
 
-        let rndA_rndBshifted = aes_decrypt(&key, &dk_rndA_rndBshifted)?;
+        let rndA_rndBshifted =
+            aes_decrypt(&key, &dk_rndA_rndBshifted).map_err(|_| ServiceError::Unauthorized)?;
 
         let rndB = self.card.get_auth_data();
+        if rndB.len() != 32 || rndA_rndBshifted.len() != 64 {
+            return Err(ServiceError::Unauthorized);
+        }
+
         let mut rndBshifted: Vec<u8> = Vec::with_capacity(32);
         rndBshifted.extend(&rndB[1..32]);
         rndBshifted.push(rndB[0]);
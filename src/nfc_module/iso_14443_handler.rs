@@ -11,6 +11,8 @@ pub struct Iso14443Handler {
 }
 
 impl Iso14443Handler {
+    pub const NAME: &'static str = "Iso14443";
+
     fn get_card_id(&mut self) -> ServiceResult<Vec<u8>> {
         if let Some(id) = self.card.card.get_id() {
             return Ok(id);
@@ -106,3 +108,89 @@ impl Iso14443Handler {
         Ok(())
     }
 }
+
+#[test]
+pub fn iso_14443_handler_test() {
+    use crate::websocket_server::WebsocketResponseMessage;
+
+    use super::nfc::mifare_utils::{self, aes_decrypt, aes_encrypt};
+    use super::nfc::Iso14443SimulationCard;
+    use super::{decode_base64 as decode, expect_message, test_application};
+
+    let card_id = hex!("01 02 03 04 05 06 07 08");
+    let key = mifare_utils::generate_key::<32>();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let (context, mut messages) = test_application().await;
+
+        let mut card = NfcCard::new(Iso14443SimulationCard::with_key(&card_id, &[0; 32]));
+        assert!(Iso14443Handler::check_compatibility(
+            &card.get_atr().unwrap()
+        ));
+        let mut handler = Iso14443Handler::new(card);
+        handler.handle_card_authentication(&context).await.unwrap();
+        let id = expect_message(
+            &mut messages,
+            "an identify request",
+            |message| match message {
+                WebsocketResponseMessage::NfcIdentifyRequest { card_id, .. } => {
+                    Some(decode(card_id))
+                }
+                _ => None,
+            },
+        )
+        .await;
+        assert_eq!(id, card_id);
+        handler.card.init(&key).unwrap();
+
+        // Challenge response with the key the app was initialised with.
+        handler
+            .handle_card_identify_response(&context, card_id.to_vec())
+            .await
+            .unwrap();
+        let ek_rndB = expect_message(
+            &mut messages,
+            "a challenge request",
+            |message| match message {
+                WebsocketResponseMessage::NfcChallengeRequest { request, .. } => {
+                    Some(decode(request))
+                }
+                _ => None,
+            },
+        )
+        .await;
+        let rndB = aes_decrypt(&key, &ek_rndB).unwrap();
+        let rndA = mifare_utils::generate_key::<32>();
+        let mut challenge = rndA.to_vec();
+        challenge.extend(&rndB[1..32]);
+        challenge.push(rndB[0]);
+        let challenge = aes_encrypt(&key, &challenge).unwrap();
+
+        handler
+            .handle_card_challenge_response(&context, card_id.to_vec(), challenge.clone())
+            .await
+            .unwrap();
+        let ek_rndAshifted =
+            expect_message(
+                &mut messages,
+                "a response request",
+                |message| match message {
+                    WebsocketResponseMessage::NfcResponseRequest { response, .. } => {
+                        Some(decode(response))
+                    }
+                    _ => None,
+                },
+            )
+            .await;
+        let rndAshifted = aes_decrypt(&key, &ek_rndAshifted).unwrap();
+        assert_eq!(rndAshifted[0..31], rndA[1..32]);
+        assert_eq!(rndAshifted[31], rndA[0]);
+
+        // A challenge is only answered once.
+        assert!(handler
+            .handle_card_challenge_response(&context, card_id.to_vec(), challenge)
+            .await
+            .is_err());
+    });
+}
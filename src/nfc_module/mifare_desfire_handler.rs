@@ -17,6 +17,8 @@ pub struct MiFareDESFireHandler {
 }
 
 impl MiFareDESFireHandler {
+    pub const NAME: &'static str = "MiFareDESFire";
+
     fn get_card_id(&mut self) -> ServiceResult<Vec<u8>> {
         if let Some(id) = self.card.card.get_id() {
             return Ok(id);
@@ -318,3 +320,94 @@ impl MiFareDESFireHandler {
         Ok(())
     }
 }
+
+#[test]
+pub fn mifare_desfire_handler_test() {
+    use crate::websocket_server::{CardTypeDto, WebsocketResponseMessage};
+
+    use super::nfc::{mifare_utils, MiFareDESFireSimulationCard};
+    use super::{decode_base64 as decode, expect_message, test_application};
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let (context, mut messages) = test_application().await;
+
+        let mut card = NfcCard::new(MiFareDESFireSimulationCard::new());
+        assert!(MiFareDESFireHandler::check_compatibility(
+            &card.get_atr().unwrap()
+        ));
+        let mut handler = MiFareDESFireHandler::new(card);
+
+        // A blank card is turned into an ascii card with a fresh key.
+        handler
+            .handle_card_register(&context, Vec::new())
+            .await
+            .unwrap();
+        let key = expect_message(
+            &mut messages,
+            "a register request",
+            |message| match message {
+                WebsocketResponseMessage::NfcRegisterRequest {
+                    card_type: CardTypeDto::AsciiMifare,
+                    data: Some(data),
+                    ..
+                } => Some(decode(data)),
+                _ => None,
+            },
+        )
+        .await;
+        handler.card.select_application(ASCII_APPLICATION).unwrap();
+        assert!(handler.card.authenticate(0, &DEFAULT_KEY).is_err());
+
+        // Challenge response with the key the server stored.
+        let card_id = handler.get_card_id().unwrap();
+        handler
+            .handle_card_identify_response(&context, card_id.clone())
+            .await
+            .unwrap();
+        let ek_rndB = expect_message(
+            &mut messages,
+            "a challenge request",
+            |message| match message {
+                WebsocketResponseMessage::NfcChallengeRequest { request, .. } => {
+                    Some(decode(request))
+                }
+                _ => None,
+            },
+        )
+        .await;
+        let rndB = mifare_utils::tdes_decrypt(&key, &ek_rndB).unwrap();
+        let rndA = mifare_utils::generate_key::<8>();
+        let mut challenge = rndA.to_vec();
+        challenge.extend(&rndB[1..8]);
+        challenge.push(rndB[0]);
+        let challenge = mifare_utils::tdes_encrypt(&key, &challenge).unwrap();
+
+        handler
+            .handle_card_challenge_response(&context, card_id, challenge)
+            .await
+            .unwrap();
+        let ek_rndAshifted =
+            expect_message(
+                &mut messages,
+                "a response request",
+                |message| match message {
+                    WebsocketResponseMessage::NfcResponseRequest { response, .. } => {
+                        Some(decode(response))
+                    }
+                    _ => None,
+                },
+            )
+            .await;
+        let rndAshifted = mifare_utils::tdes_decrypt(&key, &ek_rndAshifted).unwrap();
+        assert_eq!(rndAshifted[0..7], rndA[1..8]);
+        assert_eq!(rndAshifted[7], rndA[0]);
+
+        // The mensa application keeps the credit and the last debit.
+        assert_eq!(handler.read_mensa_data().unwrap(), (0, 0));
+        handler.write_mensa_data(1250, 0, &key).unwrap();
+        assert_eq!(handler.read_mensa_data().unwrap(), (1250, 0));
+        handler.write_mensa_data(1000, 0, &key).unwrap();
+        assert_eq!(handler.read_mensa_data().unwrap(), (1000, 250));
+    });
+}
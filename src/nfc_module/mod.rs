@@ -1,11 +1,14 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub mod nfc;
 use nfc::NfcCard;
 
 mod nfc_card_handler;
+mod reader;
+use reader::{lock_readers, Reader, ReaderMap, ReaderMessage};
 
 mod generic_nfc_handler;
 pub use generic_nfc_handler::GenericNfcHandler;
@@ -14,22 +17,27 @@ pub use mifare_desfire_handler::MiFareDESFireHandler;
 mod iso_14443_handler;
 pub use iso_14443_handler::Iso14443Handler;
 mod unsupported_card_handler;
-use tokio::runtime::Runtime;
+use tokio::runtime::Handle;
 use tokio::task;
 pub use unsupported_card_handler::UnsupportedCardHandler;
 
-use log::{error, info};
+use log::{error, info, warn};
 use pcsc::{Context, Protocols, ReaderState, Scope, ShareMode, State, PNP_NOTIFICATION};
 use tokio::fs::File;
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::sync::{mpsc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
 
 use crate::application::ApplicationResponseContext;
-use crate::websocket_server::CardTypeDto;
-use crate::ServiceResult;
+use crate::supervisor::{Backoff, Shutdown};
+use crate::websocket_server::{AuthStageDto, CardTypeDto, SessionId, ACCEPT_BACKOFF};
+use crate::{ErrorCode, ServiceError, ServiceResult};
 
+use self::nfc::auth_state::AuthStep;
 use self::nfc::simulation_card::SimulationCard;
 use self::nfc::utils;
+use self::nfc::vpcd_card;
+use self::nfc::{Iso14443SimulationCard, MiFareDESFireSimulationCard, NfcError, VpcdCard};
 use self::nfc_card_handler::NfcCardHandlerWrapper;
 
 #[derive(Debug, Clone)]
@@ -53,127 +61,242 @@ pub enum NfcCommand {
     Reauthenticate,
 }
 
+impl NfcCommand {
+    /// Card the command is meant for, `None` means the first card on the readers.
+    fn card_id(&self) -> Option<&[u8]> {
+        match self {
+            NfcCommand::IdentifyResponse { card_id, .. }
+            | NfcCommand::ChallengeResponse { card_id, .. }
+            | NfcCommand::ResponseResponse { card_id, .. }
+            | NfcCommand::Register { card_id } => Some(card_id),
+            NfcCommand::Reauthenticate => None,
+        }
+    }
+
+    /// Authentication step the command answers, if it is part of the flow.
+    fn auth_step(&self) -> Option<AuthStep> {
+        match self {
+            NfcCommand::IdentifyResponse { .. } => Some(AuthStep::IdentifyResponse),
+            NfcCommand::ChallengeResponse { .. } => Some(AuthStep::ChallengeResponse),
+            NfcCommand::ResponseResponse { .. } => Some(AuthStep::ResponseResponse),
+            NfcCommand::Register { .. } | NfcCommand::Reauthenticate => None,
+        }
+    }
+}
+
+/// A `NfcCommand` together with the websocket session that issued it, the
+/// client-chosen `request_id` that is echoed on every resulting message and
+/// the reader it is meant for.
+#[derive(Debug, Clone)]
+pub struct NfcRequest {
+    pub session: SessionId,
+    pub request_id: Option<String>,
+    pub reader: Option<String>,
+    pub command: NfcCommand,
+}
+
 pub struct NfcModule {
     context: ApplicationResponseContext,
-    recv: mpsc::Receiver<NfcCommand>,
 }
 
 impl NfcModule {
-    pub fn new(context: ApplicationResponseContext, recv: mpsc::Receiver<NfcCommand>) -> Self {
-        Self { context, recv }
+    pub fn new(context: ApplicationResponseContext) -> Self {
+        Self { context }
     }
 
-    pub async fn run(self, useSimulation: bool) -> ServiceResult<()> {
+    pub async fn run(self, useSimulation: bool, shutdown: Shutdown) -> ServiceResult<()> {
         info!("Start nfc module");
 
-        let current_cards: CardMapMutex = Arc::new(Mutex::new(HashMap::new()));
+        let readers: ReaderMap = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let runtime = Handle::current();
 
-        let loop_context = self.context;
-        let recv = self.recv;
+        let loop_context = self.context.clone();
+        let recv = loop_context.attach_nfc().await?;
 
         let spawn_context = loop_context.clone();
-        let curr_cards = current_cards.clone();
-        tokio::spawn(run_spawn(spawn_context, recv, curr_cards));
-
-        if useSimulation {
-            run_simulation(loop_context, current_cards).await;
+        let mut commands = tokio::spawn(run_spawn(
+            spawn_context,
+            recv,
+            readers.clone(),
+            shutdown.clone(),
+        ));
+
+        // Tells the blocking reader loop to stop its reader actors and give up its pcsc context.
+        let running = Arc::new(AtomicBool::new(true));
+        let mut watcher = if useSimulation {
+            tokio::spawn(run_simulation(loop_context, readers, shutdown))
+        } else if let Ok(address) = std::env::var("NFC_VPCD_LISTEN") {
+            tokio::spawn(run_vpcd(loop_context, readers, shutdown, address))
         } else {
-            task::spawn_blocking(move || run_loop(loop_context, current_cards)).await?;
-        }
+            let running = running.clone();
+            task::spawn_blocking(move || run_loop(loop_context, readers, running, runtime))
+        };
+
+        let flatten = |result: Result<ServiceResult<()>, task::JoinError>| match result {
+            Ok(result) => result,
+            Err(e) => Err(e.into()),
+        };
+
+        let result = tokio::select! {
+            // The reader actors finish the transaction in progress before they stop.
+            result = &mut commands => {
+                running.store(false, Ordering::Relaxed);
+                let watcher = flatten(watcher.await);
+                result.map_err(ServiceError::from).and(watcher)
+            }
+            result = &mut watcher => match flatten(result) {
+                // No more reader events (e.g. closed stdin), keep serving the known cards.
+                Ok(()) => commands.await.map_err(ServiceError::from),
+                Err(e) => {
+                    commands.abort();
+                    Err(e)
+                }
+            },
+        };
 
-        Ok(())
+        // The readers are gone until the module runs again.
+        self.context.send_nfc_readers(Vec::new()).await;
+        result
     }
 }
 
+/// Routes the nfc requests to the actor of the addressed reader, without waiting for the card.
 async fn run_spawn(
     context: ApplicationResponseContext,
-    mut recv: mpsc::Receiver<NfcCommand>,
-    current_cards: CardMapMutex,
+    mut recv: mpsc::Receiver<NfcRequest>,
+    readers: ReaderMap,
+    mut shutdown: Shutdown,
 ) {
-    while let Some(command) = recv.recv().await {
-        let mut current_cards = current_cards.lock().await;
-        if !current_cards.is_empty() {
-            if let Some(key) = current_cards.keys().next().cloned() {
-                if let Some(card) = current_cards.remove(&key) {
-                    if card.has_timeout_occurred() {
-                        // Card is no longer valid -> remove it.
-                        continue;
-                    }
-
-                    let card = match command {
-                        NfcCommand::Reauthenticate => {
-                            handle_card_authentication(&context, card).await
-                        }
-                        NfcCommand::IdentifyResponse { card_id, card_type } => {
-                            handle_card_identify_response(&context, card, card_id, card_type).await
-                        }
-                        NfcCommand::ChallengeResponse { card_id, challenge } => {
-                            handle_card_challenge_response(&context, card, card_id, challenge).await
-                        }
-                        NfcCommand::ResponseResponse {
-                            card_id,
-                            session_key,
-                        } => {
-                            handle_card_response_response(&context, card, card_id, session_key)
-                                .await
-                        }
-                        NfcCommand::Register { card_id } => {
-                            handle_card_register(&context, card, card_id).await
-                        }
-                    };
-
-                    current_cards.insert(key, card);
-                } else {
-                    match command {
-                        NfcCommand::IdentifyResponse { .. } => {
-                            context.send_error("NFC Reader", "No nfc card found!").await;
-                        }
-                        NfcCommand::ChallengeResponse { .. } => {
-                            context.send_error("NFC Reader", "No nfc card found!").await;
-                        }
-                        NfcCommand::ResponseResponse { .. } => {
-                            context.send_error("NFC Reader", "No nfc card found!").await;
-                        }
-                        NfcCommand::Register { .. } => {
-                            context.send_error("NFC Reader", "No nfc card found!").await;
-                        }
-                        _ => {}
-                    };
+    loop {
+        let request = tokio::select! {
+            request = recv.recv() => request,
+            _ = shutdown.requested() => None,
+        };
+        let NfcRequest {
+            session,
+            request_id,
+            reader,
+            command,
+        } = match request {
+            Some(request) => request,
+            None => break,
+        };
+
+        // Everything caused by this command is only relevant for the requesting client.
+        let context = context.reply_to(session, request_id);
+
+        let context = match &reader {
+            Some(reader) => context.for_reader(reader),
+            None => context,
+        };
+
+        let route = {
+            let readers = lock_readers(&readers);
+            reader::select_reader(&readers, reader.as_deref(), &command)
+                .map(|name| (readers[&name].sender(), name))
+        };
+
+        let error = match route {
+            Ok((sender, name)) => {
+                let message = ReaderMessage::Command(context.for_reader(&name), command);
+                match sender.send(message) {
+                    Ok(()) => continue,
+                    Err(_) => (ErrorCode::NoCard, format!("Nfc reader '{name}' is gone!")),
                 }
-            } else {
-                match command {
-                    NfcCommand::IdentifyResponse { .. } => {
-                        context.send_error("NFC Reader", "No nfc card found!").await;
-                    }
-                    NfcCommand::ChallengeResponse { .. } => {
-                        context.send_error("NFC Reader", "No nfc card found!").await;
-                    }
-                    NfcCommand::ResponseResponse { .. } => {
-                        context.send_error("NFC Reader", "No nfc card found!").await;
-                    }
-                    NfcCommand::Register { .. } => {
-                        context.send_error("NFC Reader", "No nfc card found!").await;
-                    }
-                    _ => {}
-                };
             }
+            // Nothing to re-authenticate on an idle terminal.
+            Err((ErrorCode::NoCard, _))
+                if reader.is_none() && matches!(command, NfcCommand::Reauthenticate) =>
+            {
+                continue;
+            }
+            Err(error) => error,
+        };
+
+        let (code, message) = error;
+        context.send_error(code, "NFC Reader", message).await;
+    }
+}
+
+fn run_loop(
+    context: ApplicationResponseContext,
+    readers: ReaderMap,
+    running: Arc<AtomicBool>,
+    runtime: Handle,
+) -> ServiceResult<()> {
+    let mut backoff = Backoff::from_env();
+
+    while running.load(Ordering::Relaxed) {
+        let established = Instant::now();
+        let result = Context::establish(Scope::User)
+            .and_then(|ctx| watch_readers(&context, &readers, &running, &runtime, &ctx));
+
+        let e = match result {
+            Ok(()) => break,
+            Err(e) => e,
+        };
+
+        // E.g. a restarted pcscd, the cards and readers have to be picked up again.
+        // Uses the same backoff as the module supervisor.
+        runtime.block_on(context.send_nfc_readers(Vec::new()));
+        let delay = backoff.next(established.elapsed());
+        error!(
+            "No pcsc context: {}, reconnect in {}s",
+            e,
+            delay.as_secs_f32()
+        );
+        runtime.block_on(context.send_error(
+            ErrorCode::Unavailable,
+            "NFC Reader",
+            format!("No connection to the smart card service ({e})!"),
+        ));
+
+        let reconnect = Instant::now() + delay;
+        while running.load(Ordering::Relaxed) && Instant::now() < reconnect {
+            std::thread::sleep(Duration::from_millis(100));
         }
     }
+
+    Ok(())
 }
 
-type CardMapMutex = Arc<Mutex<HashMap<String, NfcCard>>>;
+/// Follows reader and card changes until the module stops or the pcsc context fails.
+///
+/// Every reader gets an actor that owns its card, this loop only connects the cards.
+fn watch_readers(
+    context: &ApplicationResponseContext,
+    readers: &ReaderMap,
+    running: &AtomicBool,
+    runtime: &Handle,
+    ctx: &Context,
+) -> Result<(), pcsc::Error> {
+    let mut actors: HashMap<String, Reader> = HashMap::new();
+    let result = watch_reader_states(context, readers, running, runtime, ctx, &mut actors);
+
+    // Release the cards before the pcsc context is dropped.
+    for (_, actor) in actors.drain() {
+        actor.stop();
+    }
 
-fn run_loop(context: ApplicationResponseContext, current_cards: CardMapMutex) {
-    let rt = Runtime::new().unwrap();
-    let ctx = Context::establish(Scope::User).unwrap();
+    result
+}
 
+fn watch_reader_states(
+    context: &ApplicationResponseContext,
+    readers: &ReaderMap,
+    running: &AtomicBool,
+    runtime: &Handle,
+    ctx: &Context,
+    actors: &mut HashMap<String, Reader>,
+) -> Result<(), pcsc::Error> {
     let mut readers_buf = [0; 2048];
     let mut reader_states = vec![
         // Listen for reader insertions/removals, if supported.
         ReaderState::new(PNP_NOTIFICATION(), State::UNAWARE),
     ];
+    let mut present: HashSet<String> = HashSet::new();
 
-    loop {
+    while running.load(Ordering::Relaxed) {
         // Remove dead readers.
         fn is_dead(rs: &ReaderState) -> bool {
             rs.event_state().intersects(State::UNKNOWN | State::IGNORE)
@@ -181,9 +304,7 @@ fn run_loop(context: ApplicationResponseContext, current_cards: CardMapMutex) {
         reader_states.retain(|rs| !is_dead(rs));
 
         // Add new readers.
-        let names = ctx
-            .list_readers(&mut readers_buf)
-            .expect("failed to list readers");
+        let names = ctx.list_readers(&mut readers_buf)?;
         for name in names {
             if !reader_states.iter().any(|rs| rs.name() == name) {
                 // info!("Adding {:?}", name);
@@ -191,88 +312,223 @@ fn run_loop(context: ApplicationResponseContext, current_cards: CardMapMutex) {
             }
         }
 
+        let names: Vec<String> = reader_states
+            .iter()
+            .filter(|rs| rs.name() != PNP_NOTIFICATION())
+            .map(|rs| rs.name().to_str().unwrap_or("unknown").to_owned())
+            .collect();
+        let gone: Vec<String> = actors
+            .keys()
+            .filter(|name| !names.contains(name))
+            .cloned()
+            .collect();
+        let new: Vec<String> = names
+            .iter()
+            .filter(|name| !actors.contains_key(*name))
+            .cloned()
+            .collect();
+        if !gone.is_empty() || !new.is_empty() {
+            for name in gone {
+                present.remove(&name);
+                if let Some(actor) = actors.remove(&name) {
+                    actor.stop();
+                }
+            }
+            for name in new {
+                match Reader::spawn(&name, context, readers, runtime.clone()) {
+                    Ok(actor) => {
+                        actors.insert(name, actor);
+                    }
+                    Err(e) => error!("Cannot start actor of nfc reader {}: {}", name, e),
+                }
+            }
+            runtime.block_on(context.send_nfc_readers(names));
+        }
+
         // Update the view of the state to wait on.
         for rs in &mut reader_states {
             rs.sync_current_state();
         }
 
         // Wait until the state changes.
-        if ctx
-            .get_status_change(Some(Duration::from_millis(500)), &mut reader_states)
-            .is_ok()
-        {
-            let rs_states = reader_states.iter().map(|rs| {
-                (
-                    rs.name().to_owned(),
-                    rs.event_state().contains(State::PRESENT),
-                )
-            });
-
-            // Status has changed, read new states.
-            let mut current_cards = rt.block_on(current_cards.lock());
-            for (c_name, contains_state_present) in rs_states {
-                if c_name.as_c_str() != PNP_NOTIFICATION() {
-                    let name = c_name.to_str().unwrap_or("unknown").to_owned();
-                    if contains_state_present {
-                        if current_cards.contains_key(&name) {
-                            if current_cards[&name].is_in_timeout_mode() {
-                                // Remove current card.
-                                current_cards.remove(&name);
-                                info!("Remove nfc card");
-                                rt.block_on(context.send_nfc_card_removed());
-                            } else {
-                                continue;
-                            }
-                        }
-
-                        // New card, add to map und read.
-                        let card = NfcCard::new(
-                            ctx.connect(c_name.as_c_str(), ShareMode::Exclusive, Protocols::ANY)
-                                .expect("failed to connect to card"),
-                        );
-
-                        let card = rt.block_on(handle_card_authentication(&context, card));
-                        current_cards.insert(name, card);
-                    } else {
-                        // Remove current card.
-                        if current_cards.contains_key(&name) {
-                            let card = current_cards.remove(&name);
-                            info!("Remove nfc card");
-                            rt.block_on(context.send_nfc_card_removed());
-
-                            if let Some(card) = card {
-                                let card = card.remove_card();
-
-                                if let Some(card) = card {
-                                    current_cards.insert(name, card);
-                                }
-                            }
-                        }
-                    }
+        match ctx.get_status_change(Some(Duration::from_millis(500)), &mut reader_states) {
+            Ok(()) => {}
+            Err(pcsc::Error::Timeout) | Err(pcsc::Error::Cancelled) => continue,
+            Err(e) => return Err(e),
+        }
+
+        // Status has changed, read new states.
+        for rs in &reader_states {
+            if rs.name() == PNP_NOTIFICATION() {
+                continue;
+            }
+            let name = rs.name().to_str().unwrap_or("unknown").to_owned();
+            let actor = match actors.get(&name) {
+                Some(actor) => actor,
+                None => continue,
+            };
+
+            if !rs.event_state().contains(State::PRESENT) {
+                if present.remove(&name) {
+                    actor.send(ReaderMessage::Removed);
+                }
+                continue;
+            }
+            if present.contains(&name) {
+                continue;
+            }
+
+            // New card, connect and hand it to the actor. A card that is pulled during
+            // connect or glitches is skipped until it is presented again.
+            match ctx.connect(rs.name(), ShareMode::Exclusive, Protocols::ANY) {
+                Ok(card) => {
+                    present.insert(name);
+                    actor.send(ReaderMessage::Inserted(NfcCard::new(card)));
+                }
+                Err(e) => {
+                    warn!("Cannot connect to nfc card on reader {}: {}", name, e);
+                    runtime.block_on(context.for_reader(&name).send_error(
+                        ErrorCode::CommunicationError,
+                        "NFC Reader",
+                        format!("Could not connect to NFC card ({e})!"),
+                    ));
                 }
             }
         }
     }
+
+    Ok(())
 }
 
-async fn run_simulation(context: ApplicationResponseContext, current_cards: CardMapMutex) {
-    let mut reader = std_reader::StdReader::new().unwrap();
+/// Reads the card put on the simulated reader from `NFC_SIMULATION_CARD`
+/// (`mifare-classic`, `mifare-desfire` or `host-card-emulation`), defaults to
+/// `mifare-classic`.
+///
+/// The DESFire card and the phone keep their memory while the terminal runs, so
+/// a card that was registered can be identified after it was put on the reader again.
+fn simulation_card_from_env() -> Box<dyn Fn() -> NfcCard + Send> {
+    match std::env::var("NFC_SIMULATION_CARD").as_deref() {
+        Ok("mifare-desfire") => {
+            let card = MiFareDESFireSimulationCard::new();
+            Box::new(move || NfcCard::new(card.clone()))
+        }
+        Ok("host-card-emulation") => {
+            let phone = Iso14443SimulationCard::new();
+            Box::new(move || NfcCard::new(phone.clone()))
+        }
+        Ok("mifare-classic") | Err(_) => Box::new(|| NfcCard::new(SimulationCard::new())),
+        Ok(value) => {
+            warn!(
+                "Unknown NFC_SIMULATION_CARD '{}', simulate a mifare classic card instead",
+                value
+            );
+            Box::new(|| NfcCard::new(SimulationCard::new()))
+        }
+    }
+}
 
-    while let Some(code) = reader.get_next_code().await.unwrap() {
+async fn run_simulation(
+    context: ApplicationResponseContext,
+    readers: ReaderMap,
+    mut shutdown: Shutdown,
+) -> ServiceResult<()> {
+    let new_card = simulation_card_from_env();
+    let mut stdin = std_reader::StdReader::new()?;
+    let actor = Reader::spawn("demo", &context, &readers, Handle::current())?;
+    context.send_nfc_readers(vec!["demo".into()]).await;
+
+    let mut present = false;
+    loop {
+        let code = tokio::select! {
+            code = stdin.get_next_code() => code,
+            _ = shutdown.requested() => Ok(None),
+        };
+        let code = match code {
+            Ok(Some(code)) => code,
+            Ok(None) => break,
+            Err(e) => {
+                task::spawn_blocking(move || actor.stop()).await?;
+                return Err(e);
+            }
+        };
         let code = code.trim().to_owned();
 
-        let mut current_cards = current_cards.lock().await;
-
-        if current_cards.is_empty() {
-            let card = NfcCard::simulate(SimulationCard::new());
-            let card = handle_card_authentication(&context, card).await;
-            current_cards.insert("demo".into(), card);
+        present = !present;
+        if present {
+            actor.send(ReaderMessage::Inserted(new_card()));
         } else {
-            current_cards.clear();
-            info!("Remove nfc card");
-            context.send_nfc_card_removed().await;
+            actor.send(ReaderMessage::Removed);
         }
     }
+
+    // Keep serving the simulated card until the module stops.
+    shutdown.requested().await;
+    task::spawn_blocking(move || actor.stop()).await?;
+
+    Ok(())
+}
+
+/// Acts as the reader for vsmartcard's `vicc`, every connection is a card put
+/// on the reader and closing it removes the card.
+async fn run_vpcd(
+    context: ApplicationResponseContext,
+    readers: ReaderMap,
+    mut shutdown: Shutdown,
+    address: String,
+) -> ServiceResult<()> {
+    let address = vpcd_card::vpcd_listen_address(&address);
+    let listener = TcpListener::bind(&address).await?;
+    info!("Listen for virtual cards (vpcd) on {}", address);
+
+    let actor = Reader::spawn("vpcd", &context, &readers, Handle::current())?;
+    context.send_nfc_readers(vec!["vpcd".into()]).await;
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.requested() => break,
+        };
+
+        let stream = match accepted {
+            Ok((stream, address)) => {
+                info!("Virtual card connected from {}", address);
+                stream.into_std()
+            }
+            Err(e) => {
+                error!("Cannot accept virtual card: {}", e);
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        // A virtual card may take its time to answer the handshake.
+        let card = match stream {
+            Ok(stream) => {
+                let connect = task::spawn_blocking(move || VpcdCard::connect(stream));
+                tokio::select! {
+                    card = connect => card?,
+                    _ = shutdown.requested() => break,
+                }
+            }
+            Err(e) => Err(NfcError::from(e)),
+        };
+        match card {
+            Ok(card) => actor.send(ReaderMessage::Inserted(NfcCard::new(card))),
+            Err(e) => {
+                warn!("Cannot connect to virtual card: {}", e);
+                context
+                    .for_reader("vpcd")
+                    .send_error(
+                        ErrorCode::CommunicationError,
+                        "NFC Reader",
+                        format!("Could not connect to NFC card ({e})!"),
+                    )
+                    .await;
+            }
+        }
+    }
+
+    task::spawn_blocking(move || actor.stop()).await?;
+    Ok(())
 }
 
 async fn handle_card_authentication(
@@ -280,13 +536,22 @@ async fn handle_card_authentication(
     card: NfcCard,
 ) -> NfcCard {
     let mut handler = NfcCardHandlerWrapper::new(card);
-    if let Err(e) = handler.handle_card_authentication(context).await {
-        error!("Cannot authenticate card: {:?}", e);
-        context
-            .send_error("NFC Reader", "Could not authenticate NFC card!")
-            .await
+    let result = handler.handle_card_authentication(context).await;
+    let mut card = handler.finish();
+    match result {
+        Ok(_) => card.set_auth_stage(AuthStageDto::Detected),
+        Err(e) => {
+            error!("Cannot authenticate card: {}", e);
+            context
+                .send_error(
+                    e.code(),
+                    "NFC Reader",
+                    error_message("Could not authenticate NFC card!", &e),
+                )
+                .await
+        }
     }
-    handler.finish()
+    card
 }
 
 async fn handle_card_register(
@@ -300,7 +565,11 @@ async fn handle_card_register(
         Err(e) => {
             error!("Could not register nfc card: {}", e);
             context
-                .send_error("NFC Reader", "Could not register NFC card!")
+                .send_error(
+                    e.code(),
+                    "NFC Reader",
+                    error_message("Could not register NFC card!", &e),
+                )
                 .await
         }
     }
@@ -315,19 +584,24 @@ async fn handle_card_identify_response(
 ) -> NfcCard {
     card.set_card_type(Some(card_type));
     let mut handler = NfcCardHandlerWrapper::new(card);
-    match handler
+    let result = handler
         .handle_card_identify_response(context, card_id)
-        .await
-    {
-        Ok(_) => {}
+        .await;
+    let mut card = handler.finish();
+    match result {
+        Ok(_) => card.set_auth_stage(AuthStageDto::Identified),
         Err(e) => {
             error!("Could not identify nfc card: {}", e);
             context
-                .send_error("NFC Reader", "Could not identify NFC card!")
+                .send_error(
+                    e.code(),
+                    "NFC Reader",
+                    error_message("Could not identify NFC card!", &e),
+                )
                 .await
         }
     }
-    handler.finish()
+    card
 }
 
 async fn handle_card_challenge_response(
@@ -337,19 +611,24 @@ async fn handle_card_challenge_response(
     challenge: Vec<u8>,
 ) -> NfcCard {
     let mut handler = NfcCardHandlerWrapper::new(card);
-    match handler
+    let result = handler
         .handle_card_challenge_response(context, card_id, challenge)
-        .await
-    {
-        Ok(_) => {}
+        .await;
+    let mut card = handler.finish();
+    match result {
+        Ok(_) => card.set_auth_stage(AuthStageDto::Challenged),
         Err(e) => {
             error!("Could not challenge nfc card: {}", e);
             context
-                .send_error("NFC Reader", "Could not challenge NFC card!")
+                .send_error(
+                    e.code(),
+                    "NFC Reader",
+                    error_message("Could not challenge NFC card!", &e),
+                )
                 .await
         }
     }
-    handler.finish()
+    card
 }
 
 async fn handle_card_response_response(
@@ -359,19 +638,40 @@ async fn handle_card_response_response(
     session_key: Vec<u8>,
 ) -> NfcCard {
     let handler = NfcCardHandlerWrapper::new(card);
-    match handler
+    let result = handler
         .handle_card_response_response(context, card_id, session_key)
-        .await
-    {
-        Ok(_) => {}
+        .await;
+    let mut card = handler.finish();
+    match result {
+        Ok(_) => card.set_auth_stage(AuthStageDto::Authenticated),
         Err(e) => {
             error!("Could not response nfc card: {}", e);
             context
-                .send_error("NFC Reader", "Could not response NFC card!")
+                .send_error(
+                    e.code(),
+                    "NFC Reader",
+                    error_message("Could not response NFC card!", &e),
+                )
                 .await
         }
     }
-    handler.finish()
+    card
+}
+
+/// Appends the details of a card error, so a failure can be diagnosed from the frontend.
+fn error_message(message: &str, error: &ServiceError) -> String {
+    match error {
+        ServiceError::NfcError(error) => format!("{message} ({error})"),
+        _ => message.to_owned(),
+    }
+}
+
+/// Names of the card handlers compiled into this terminal.
+pub fn card_handlers() -> Vec<String> {
+    NfcCardHandlerWrapper::HANDLERS
+        .iter()
+        .map(|name| (*name).to_owned())
+        .collect()
 }
 
 pub async fn identify_atr(atr: &[u8]) -> Vec<String> {
@@ -424,3 +724,45 @@ mod std_reader {
         }
     }
 }
+
+/// Messages of the application to the websocket clients.
+#[cfg(test)]
+type TestMessages = mpsc::Receiver<(
+    crate::websocket_server::Recipient,
+    crate::websocket_server::WebsocketResponse,
+)>;
+
+/// Runs an application for the handler tests, returns its context and the
+/// messages it sends to the websocket clients.
+#[cfg(test)]
+async fn test_application() -> (ApplicationResponseContext, TestMessages) {
+    let application = crate::application::Application::new(true);
+    let context = application.get_response_context();
+    let request_context = application.get_request_context();
+    tokio::spawn(application.run());
+    let messages = request_context.attach_websocket().await.unwrap();
+
+    (context, messages)
+}
+
+/// Waits for the next message to the websocket clients and extracts its payload,
+/// `expected` names the message in the failure.
+#[cfg(test)]
+async fn expect_message<T>(
+    messages: &mut TestMessages,
+    expected: &str,
+    extract: impl FnOnce(crate::websocket_server::WebsocketResponseMessage) -> Option<T>,
+) -> T {
+    let message = messages.recv().await.unwrap().1.message;
+    let description = format!("{message:?}");
+    extract(message).unwrap_or_else(|| panic!("Expected {expected}, got {description}"))
+}
+
+/// Decodes base64 encoded card data of a websocket message.
+#[cfg(test)]
+fn decode_base64(value: String) -> Vec<u8> {
+    use base64::engine::general_purpose;
+    use base64::Engine;
+
+    general_purpose::STANDARD.decode(value).unwrap()
+}
@@ -0,0 +1,148 @@
+use std::time::{Duration, Instant};
+
+use crate::{config::env_seconds, websocket_server::AuthStageDto, ErrorCode};
+
+const DEFAULT_STEP_TIMEOUT: u64 = 30;
+
+/// Reads how long the backend may take to answer a single step of the
+/// authentication from `NFC_AUTH_STEP_TIMEOUT` in seconds, defaults to 30.
+fn step_timeout_from_env() -> Duration {
+    env_seconds("NFC_AUTH_STEP_TIMEOUT", DEFAULT_STEP_TIMEOUT)
+}
+
+/// Answer of the backend that moves a card session forward.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum AuthStep {
+    IdentifyResponse,
+    ChallengeResponse,
+    ResponseResponse,
+}
+
+impl AuthStep {
+    /// Stage a card has to be in to accept this step.
+    fn required_stage(self) -> AuthStageDto {
+        match self {
+            AuthStep::IdentifyResponse => AuthStageDto::Detected,
+            AuthStep::ChallengeResponse => AuthStageDto::Identified,
+            AuthStep::ResponseResponse => AuthStageDto::Challenged,
+        }
+    }
+}
+
+/// Progress of a card session through the identify/challenge/response flow.
+///
+/// Every stage that waits for the backend has a deadline, after which the
+/// session expires and the card has to be re-authenticated.
+#[derive(Debug)]
+pub struct AuthState {
+    stage: AuthStageDto,
+    deadline: Option<Instant>,
+    step_timeout: Duration,
+}
+
+impl AuthState {
+    pub fn new() -> Self {
+        Self::with_step_timeout(step_timeout_from_env())
+    }
+
+    pub fn with_step_timeout(step_timeout: Duration) -> Self {
+        Self {
+            stage: AuthStageDto::Detected,
+            deadline: None,
+            step_timeout,
+        }
+    }
+
+    pub fn stage(&self) -> AuthStageDto {
+        self.stage
+    }
+
+    /// Moves to `stage` and restarts the deadline if the backend has to answer again.
+    pub fn advance(&mut self, stage: AuthStageDto) {
+        self.stage = stage;
+        self.deadline = match stage {
+            AuthStageDto::Detected | AuthStageDto::Identified | AuthStageDto::Challenged => {
+                Some(Instant::now() + self.step_timeout)
+            }
+            _ => None,
+        };
+    }
+
+    /// Checks that `step` is the next one, the error is meant for the client.
+    pub fn check(&self, step: AuthStep, now: Instant) -> Result<(), (ErrorCode, String)> {
+        if self.stage == AuthStageDto::Expired || self.is_overdue(now) {
+            return Err((
+                ErrorCode::Timeout,
+                "NFC authentication timed out, re-authenticate the card!".into(),
+            ));
+        }
+
+        let required = step.required_stage();
+        if self.stage != required {
+            return Err((
+                ErrorCode::UnexpectedCommand,
+                format!(
+                    "Unexpected {:?}, the card is {:?} but has to be {:?}!",
+                    step, self.stage, required
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Expires the session if its deadline has passed, returns whether it did.
+    pub fn expire_if_overdue(&mut self, now: Instant) -> bool {
+        if self.stage == AuthStageDto::Expired || !self.is_overdue(now) {
+            return false;
+        }
+
+        self.stage = AuthStageDto::Expired;
+        self.deadline = None;
+        true
+    }
+
+    fn is_overdue(&self, now: Instant) -> bool {
+        self.deadline
+            .map(|deadline| now > deadline)
+            .unwrap_or(false)
+    }
+}
+
+impl Default for AuthState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+pub fn auth_state_test() {
+    let mut state = AuthState::with_step_timeout(Duration::from_secs(10));
+    let now = Instant::now();
+
+    state.advance(AuthStageDto::Detected);
+    assert!(state.check(AuthStep::IdentifyResponse, now).is_ok());
+    assert_eq!(
+        state.check(AuthStep::ChallengeResponse, now).unwrap_err().0,
+        ErrorCode::UnexpectedCommand
+    );
+
+    state.advance(AuthStageDto::Identified);
+    assert!(state.check(AuthStep::ChallengeResponse, now).is_ok());
+    assert!(state.check(AuthStep::IdentifyResponse, now).is_err());
+
+    let later = now + Duration::from_secs(11);
+    assert_eq!(
+        state
+            .check(AuthStep::ChallengeResponse, later)
+            .unwrap_err()
+            .0,
+        ErrorCode::Timeout
+    );
+    assert!(state.expire_if_overdue(later));
+    assert!(!state.expire_if_overdue(later));
+    assert_eq!(state.stage(), AuthStageDto::Expired);
+
+    state.advance(AuthStageDto::Authenticated);
+    assert!(!state.expire_if_overdue(later + Duration::from_secs(60)));
+}
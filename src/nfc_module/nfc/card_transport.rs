@@ -0,0 +1,66 @@
+use log::trace;
+use pcsc;
+
+use super::NfcResult;
+
+/// Connection to a card, implemented by every card backend.
+///
+/// The card handlers only talk to a card through this trait, so a new backend
+/// like a remote reader or an emulated card works with all of them.
+pub trait CardTransport: Send {
+    /// Sends an APDU, the response includes the status words.
+    fn transmit(&self, query: &[u8]) -> NfcResult<Vec<u8>>;
+
+    /// Reads an attribute of the card or the reader.
+    fn get_attribute(&self, attribute: pcsc::Attribute) -> NfcResult<Vec<u8>>;
+
+    /// Answer to reset of the card.
+    fn atr(&self) -> NfcResult<Vec<u8>> {
+        self.get_attribute(pcsc::Attribute::AtrString)
+    }
+
+    /// Connects to the card again, e.g. after it was reset by another application.
+    fn reconnect(&mut self) -> NfcResult<()>;
+
+    /// Releases the card without resetting it, a dropped card is reset instead.
+    fn disconnect(self: Box<Self>) -> NfcResult<()>;
+
+    /// Whether the card is still there, polled for backends whose reader does
+    /// not report removals on its own.
+    fn is_present(&self) -> bool {
+        true
+    }
+}
+
+impl CardTransport for pcsc::Card {
+    fn transmit(&self, query: &[u8]) -> NfcResult<Vec<u8>> {
+        trace!("transmit {:X?}", query);
+        let mut data_buf = [0; pcsc::MAX_BUFFER_SIZE];
+        let data = pcsc::Card::transmit(self, query, &mut data_buf)?;
+
+        Ok(data.to_vec())
+    }
+
+    fn get_attribute(&self, attribute: pcsc::Attribute) -> NfcResult<Vec<u8>> {
+        let data_len = self.get_attribute_len(attribute)?;
+
+        let mut data_buf = vec![0; data_len];
+        let data = pcsc::Card::get_attribute(self, attribute, &mut data_buf)?;
+
+        Ok(data.to_vec())
+    }
+
+    fn reconnect(&mut self) -> NfcResult<()> {
+        pcsc::Card::reconnect(
+            self,
+            pcsc::ShareMode::Exclusive,
+            pcsc::Protocols::ANY,
+            pcsc::Disposition::LeaveCard,
+        )?;
+        Ok(())
+    }
+
+    fn disconnect(self: Box<Self>) -> NfcResult<()> {
+        pcsc::Card::disconnect(*self, pcsc::Disposition::LeaveCard).map_err(|(_, e)| e.into())
+    }
+}
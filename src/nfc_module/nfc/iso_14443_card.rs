@@ -12,74 +12,68 @@ impl Iso14443Card {
         Iso14443Card { card }
     }
 
-    fn transmit(&self, command: u8, data: &[u8]) -> NfcResult<(bool, Vec<u8>)> {
+    fn transmit(&self, command_name: &'static str, command: u8, data: &[u8]) -> NfcResult<Vec<u8>> {
         let mut raw = Vec::<u8>::with_capacity(data.len() + 1);
 
         raw.push(command);
         raw.extend(data);
 
-        self.transmit_raw(&raw)
+        self.transmit_raw(command_name, &raw)
     }
 
-    fn transmit_raw(&self, data: &[u8]) -> NfcResult<(bool, Vec<u8>)> {
+    /// Sends a raw command and checks the leading status byte of the response.
+    fn transmit_raw(&self, command_name: &'static str, data: &[u8]) -> NfcResult<Vec<u8>> {
         info!("  Send Command: l={}, data={:2X?}", data.len(), data);
 
-        let mut data = self.card.transmit(data)?;
+        let mut data = self.card.transmit(data).command(command_name)?;
 
         if data.is_empty() {
-            return Err(NfcError::UnknownError);
+            return Err(NfcError::new(NfcErrorKind::CommunicationError).with_command(command_name));
         }
 
         info!("   --> l={}, data={:2X?}", data.len(), data);
-        let status = data.remove(0) == 0x00;
+        let status = data.remove(0);
         info!("   --> {:2X?}, l={}, data={:2X?}", status, data.len(), data);
 
-        Ok((status, data))
+        if status != 0x00 {
+            return Err(NfcError::new(NfcErrorKind::UnknownError)
+                .with_command(command_name)
+                .with_status(CardStatus::Byte(status)));
+        }
+
+        Ok(data)
     }
 
     pub fn get_id(&self) -> NfcResult<Vec<u8>> {
-        let (success, id) = self.transmit_raw(&[
-            0x00, // Class
-            0xA4, // INS
-            0x04, // P1
-            0x00, // P2
-            0x07, // Lc
-            0xF0, 0x00, 0x00, 0x00, 0xC0, 0xFF, 0xEE,
-        ])?;
-
-        if !success {
-            return Err(NfcError::UnknownError);
-        }
-
-        Ok(id)
+        self.transmit_raw(
+            "select_application",
+            &[
+                0x00, // Class
+                0xA4, // INS
+                0x04, // P1
+                0x00, // P2
+                0x07, // Lc
+                0xF0, 0x00, 0x00, 0x00, 0xC0, 0xFF, 0xEE,
+            ],
+        )
     }
 
     #[allow(non_snake_case)]
     pub fn authenticate_phase1(&self) -> NfcResult<Vec<u8>> {
-        let (success, ek_rndB) =
-            self.transmit(0x10, &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])?;
-        if !success {
-            return Err(NfcError::UnknownError);
-        }
-
-        Ok(ek_rndB)
+        self.transmit(
+            "authenticate_phase1",
+            0x10,
+            &[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        )
     }
 
     #[allow(non_snake_case)]
     pub fn authenticate_phase2(&self, dk_rndA_rndBshifted: &[u8]) -> NfcResult<Vec<u8>> {
-        let (success, ek_rndAshifted_card) = self.transmit(0x11, dk_rndA_rndBshifted)?;
-        if !success {
-            return Err(NfcError::UnknownError);
-        }
-
-        Ok(ek_rndAshifted_card)
+        self.transmit("authenticate_phase2", 0x11, dk_rndA_rndBshifted)
     }
 
     pub fn init(&self, key: &[u8]) -> NfcResult<()> {
-        let (success, _) = self.transmit(0x20, key)?;
-        if !success {
-            return Err(NfcError::UnknownError);
-        }
+        self.transmit("init", 0x20, key)?;
 
         Ok(())
     }
@@ -0,0 +1,149 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::mifare_desfire::Status;
+use super::mifare_utils::{self, aes_decrypt, aes_encrypt};
+use super::{CardTransport, NfcResult};
+
+const ATR: [u8; 5] = hex!("3B 80 80 01 01");
+const SELECT_APPLICATION: [u8; 5] = hex!("00 A4 04 00 07");
+const ASCII_APPLICATION: [u8; 7] = hex!("F0 00 00 00 C0 FF EE");
+const CARD_ID: [u8; 16] = hex!("A5 C1 1B A7 00 00 00 00 00 00 00 00 00 00 00 01");
+
+/// Status words of the phone if no app is registered for the command.
+const FILE_NOT_FOUND: [u8; 2] = hex!("6A 82");
+
+const RANDOM_SIZE: usize = 32;
+const KEY_SIZE: usize = 32;
+
+/// Phone running the ascii-pay app with host card emulation, for tests and the simulation mode.
+///
+/// Answers the select of the ascii application with its card id and does the
+/// challenge response with its key, like the generic nfc cards. A fresh phone
+/// uses the all zero key until the app is initialised with a new one. A clone
+/// is the same phone put on the reader again.
+pub struct Iso14443SimulationCard {
+    card_id: Vec<u8>,
+    key: Arc<Mutex<Vec<u8>>>,
+    session: Mutex<Session>,
+}
+
+#[derive(Default)]
+struct Session {
+    selected: bool,
+    rnd_b: Option<Vec<u8>>,
+}
+
+impl Iso14443SimulationCard {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Self::with_key(&CARD_ID, &[0; KEY_SIZE])
+    }
+
+    pub fn with_key(card_id: &[u8], key: &[u8]) -> Self {
+        Self {
+            card_id: card_id.to_vec(),
+            key: Arc::new(Mutex::new(key.to_vec())),
+            session: Mutex::new(Session::default()),
+        }
+    }
+
+    fn execute(&self, session: &mut Session, query: &[u8]) -> Result<Vec<u8>, Status> {
+        let (&command, data) = query.split_first().ok_or(Status::LengthError)?;
+        let key = lock(&self.key).clone();
+
+        match command {
+            0x10 => {
+                let rnd_b = mifare_utils::generate_key::<RANDOM_SIZE>().to_vec();
+                let ek_rnd_b = aes_encrypt(&key, &rnd_b).map_err(|_| Status::IntegrityError)?;
+                session.rnd_b = Some(rnd_b);
+                Ok(ek_rnd_b)
+            }
+            0x11 => {
+                let rnd_b = session.rnd_b.take().ok_or(Status::AuthenticationError)?;
+                if data.len() != 2 * RANDOM_SIZE {
+                    return Err(Status::LengthError);
+                }
+                let rnd_a_rnd_b = aes_decrypt(&key, data).map_err(|_| Status::IntegrityError)?;
+                if rnd_a_rnd_b[RANDOM_SIZE..] != rotate_left(&rnd_b) {
+                    return Err(Status::AuthenticationError);
+                }
+
+                aes_encrypt(&key, &rotate_left(&rnd_a_rnd_b[..RANDOM_SIZE]))
+                    .map_err(|_| Status::IntegrityError)
+            }
+            0x20 => {
+                if data.len() != KEY_SIZE {
+                    return Err(Status::LengthError);
+                }
+                *lock(&self.key) = data.to_vec();
+                Ok(Vec::new())
+            }
+            _ => Err(Status::IllegalCommandCode),
+        }
+    }
+}
+
+impl Clone for Iso14443SimulationCard {
+    fn clone(&self) -> Self {
+        Self {
+            card_id: self.card_id.clone(),
+            key: self.key.clone(),
+            session: Mutex::new(Session::default()),
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn rotate_left(value: &[u8]) -> Vec<u8> {
+    let mut rotated = value[1..].to_vec();
+    rotated.push(value[0]);
+    rotated
+}
+
+impl CardTransport for Iso14443SimulationCard {
+    fn transmit(&self, query: &[u8]) -> NfcResult<Vec<u8>> {
+        let mut session = lock(&self.session);
+
+        if let Some(aid) = query.strip_prefix(&SELECT_APPLICATION) {
+            session.selected = aid == ASCII_APPLICATION;
+            session.rnd_b = None;
+            if !session.selected {
+                return Ok(FILE_NOT_FOUND.into());
+            }
+
+            let mut response = vec![Status::OperationOk.code()];
+            response.extend(&self.card_id);
+            return Ok(response);
+        }
+        if !session.selected {
+            return Ok(FILE_NOT_FOUND.into());
+        }
+
+        let (status, data) = match self.execute(&mut session, query) {
+            Ok(data) => (Status::OperationOk, data),
+            Err(status) => (status, Vec::new()),
+        };
+        let mut response = vec![status.code()];
+        response.extend(data);
+        Ok(response)
+    }
+
+    fn get_attribute(&self, attribute: pcsc::Attribute) -> NfcResult<Vec<u8>> {
+        match attribute {
+            pcsc::Attribute::AtrString => Ok(ATR.into()),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn reconnect(&mut self) -> NfcResult<()> {
+        *lock(&self.session) = Session::default();
+        Ok(())
+    }
+
+    fn disconnect(self: Box<Self>) -> NfcResult<()> {
+        Ok(())
+    }
+}
@@ -36,51 +36,41 @@ impl Encryption {
         Ok(match self {
             Encryption::PlainText => data.to_vec(),
             Encryption::MACed(key) => {
+                if data.len() < 4 {
+                    return Err(NfcErrorKind::IntegrityError.into());
+                }
                 let mac = &mifare_utils::mac(key, &data[0..(data.len() - 4)])?;
                 let mut vec: Vec<u8> = data.to_vec();
                 if mac.len() < 4 {
-                    return Err(NfcError::IntegrityError);
+                    return Err(NfcErrorKind::IntegrityError.into());
                 }
                 if vec.pop().expect("Length check already passed") != mac[mac.len() - 1] {
-                    return Err(NfcError::IntegrityError);
+                    return Err(NfcErrorKind::IntegrityError.into());
                 }
                 if vec.pop().expect("Length check already passed") != mac[mac.len() - 2] {
-                    return Err(NfcError::IntegrityError);
+                    return Err(NfcErrorKind::IntegrityError.into());
                 }
                 if vec.pop().expect("Length check already passed") != mac[mac.len() - 3] {
-                    return Err(NfcError::IntegrityError);
+                    return Err(NfcErrorKind::IntegrityError.into());
                 }
                 if vec.pop().expect("Length check already passed") != mac[mac.len() - 4] {
-                    return Err(NfcError::IntegrityError);
+                    return Err(NfcErrorKind::IntegrityError.into());
                 }
                 vec
             }
             Encryption::Encrypted(key) => {
                 let data = mifare_utils::tdes_decrypt(key, data)?;
-                let mut d = data.clone();
-                d.pop();
-                d.pop();
-
-                let mut found = false;
-                for i in 0..9 {
-                    let crc = mifare_utils::crc_checksum(&d);
-                    let crc_position = d.len();
-
-                    if crc_position + 1 == data.len() {
-                        return Err(NfcError::IntegrityError);
-                    }
 
-                    if data[crc_position] == crc[0] && data[crc_position + 1] == crc[1] {
-                        found = true;
-                        break;
-                    }
-
-                    d.pop();
-                }
-                if found {
-                    d
-                } else {
-                    return Err(NfcError::IntegrityError);
+                // The CRC is followed by up to 7 zero bytes of padding. The CRC over
+                // data and CRC is zero as well, so the first match is the right one.
+                let first = data.len().saturating_sub(9);
+                let position = (first..data.len().saturating_sub(1)).find(|&position| {
+                    data[position..position + 2] == mifare_utils::crc_checksum(&data[..position])
+                        && data[position + 2..].iter().all(|b| *b == 0)
+                });
+                match position {
+                    Some(position) => data[..position].to_vec(),
+                    None => return Err(NfcErrorKind::IntegrityError.into()),
                 }
             }
         })
@@ -113,6 +103,8 @@ pub enum Status {
     EepromError,
     FileNotFound,
     FileIntegrityError,
+    /// Status byte this implementation does not know.
+    Unknown(u8),
 }
 
 impl Status {
@@ -140,34 +132,61 @@ impl Status {
             0xEE => Status::EepromError,
             0xF0 => Status::FileNotFound,
             0xF1 => Status::FileIntegrityError,
-            _ => panic!("Unknown status code: {code}"),
+            _ => Status::Unknown(code),
         }
     }
 
-    pub fn to_result_data<T>(self, value: T, command_name: &str) -> NfcResult<T> {
-        let result = match self {
-            Status::OperationOk | Status::NoChanges | Status::AdditionalFrame => Ok(value),
+    /// Raw status byte as sent by the card.
+    pub fn code(self) -> u8 {
+        match self {
+            Status::OperationOk => 0x00,
+            Status::NoChanges => 0x0C,
+            Status::OutOfEepromError => 0x0E,
+            Status::IllegalCommandCode => 0x1C,
+            Status::IntegrityError => 0x1E,
+            Status::NoSuchKey => 0x40,
+            Status::LengthError => 0x7E,
+            Status::PermissionDenied => 0x9D,
+            Status::ParameterError => 0x9E,
+            Status::ApplicationNotFound => 0xA0,
+            Status::ApplIntegrityError => 0xA1,
+            Status::AuthenticationError => 0xAE,
+            Status::AdditionalFrame => 0xAF,
+            Status::BoundaryError => 0xBE,
+            Status::PiccIntegrityError => 0xC1,
+            Status::CommandAborted => 0xCA,
+            Status::PiccDisabledError => 0xCD,
+            Status::CountError => 0xCE,
+            Status::DuplicateError => 0xDE,
+            Status::EepromError => 0xEE,
+            Status::FileNotFound => 0xF0,
+            Status::FileIntegrityError => 0xF1,
+            Status::Unknown(code) => code,
+        }
+    }
+
+    pub fn to_result_data<T>(self, value: T, command_name: &'static str) -> NfcResult<T> {
+        let kind = match self {
+            Status::OperationOk | Status::NoChanges | Status::AdditionalFrame => return Ok(value),
             Status::FileIntegrityError
             | Status::PiccIntegrityError
             | Status::ApplIntegrityError
-            | Status::IntegrityError => Err(NfcError::IntegrityError),
+            | Status::IntegrityError => NfcErrorKind::IntegrityError,
             Status::PermissionDenied | Status::AuthenticationError => {
-                Err(NfcError::PermissionDenied)
+                NfcErrorKind::PermissionDenied
             }
-            _ => Err(NfcError::UnknownError),
+            _ => NfcErrorKind::UnknownError,
         };
 
-        if result.is_err() {
-            error!(
-                "NFC commuincation error: Command: {:?}, Error: {:?}",
-                command_name, self
-            );
-        }
+        let error = NfcError::new(kind)
+            .with_command(command_name)
+            .with_status(CardStatus::Byte(self.code()));
+        error!("NFC communication error: {} ({:?})", error, self);
 
-        result
+        Err(error)
     }
 
-    pub fn to_result(self, command_name: &str) -> NfcResult<()> {
+    pub fn to_result(self, command_name: &'static str) -> NfcResult<()> {
         self.to_result_data((), command_name)
     }
 }
@@ -286,7 +305,7 @@ impl Serializable for KeySettingsAccessRights {
             0xD0 => KeySettingsAccessRights::Key0D,
             0xE0 => KeySettingsAccessRights::SameKey,
             0xF0 => KeySettingsAccessRights::KeysFrozen,
-            _ => panic!("Illegal KeySettingsAccessRights!"),
+            _ => return Err(NfcErrorKind::ByteParseError.into()),
         })
     }
 
@@ -404,7 +423,7 @@ impl Serializable for FileSettingsAccessRightsKey {
             0xD => FileSettingsAccessRightsKey::Key0D,
             0xE => FileSettingsAccessRightsKey::Free,
             0xF => FileSettingsAccessRightsKey::Deny,
-            _ => panic!("Illegal KeySettingsAccessRights!"),
+            _ => return Err(NfcErrorKind::ByteParseError.into()),
         })
     }
 
@@ -446,7 +465,7 @@ impl Serializable for FileSettingsCommunication {
             0x02 | 0x00 => Ok(FileSettingsCommunication::PlainText),
             0x01 => Ok(FileSettingsCommunication::MACed),
             0x03 => Ok(FileSettingsCommunication::Enciphered),
-            _ => Err(NfcError::UnknownError),
+            _ => Err(NfcErrorKind::ByteParseError.into()),
         }
     }
 
@@ -560,7 +579,70 @@ impl Serializable for FileSettings {
             0x00 | 0x01 => FileSettings::data_file_from_bytes(cursor),
             0x02 => FileSettings::value_file_from_bytes(cursor),
             0x03 | 0x04 => FileSettings::record_file_from_bytes(cursor),
-            _ => Err(NfcError::UnknownError),
+            _ => Err(NfcErrorKind::ByteParseError.into()),
         }
     }
 }
+
+#[test]
+pub fn malformed_card_data_test() {
+    assert_eq!(Status::parse(0x42), Status::Unknown(0x42));
+    assert_eq!(Status::Unknown(0x42).code(), 0x42);
+    assert!(Status::Unknown(0x42).to_result("test").is_err());
+
+    let key = hex!("00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00");
+    let inputs: [&[u8]; 6] = [
+        &[],
+        &[0x00],
+        &[0x02, 0x03],
+        &[0x00, 0x00, 0x00, 0x00],
+        &[0xFF; 8],
+        &[0x01; 17],
+    ];
+    for input in inputs {
+        let _ = FileSettings::from_slice(input);
+        let _ = Version::from_slice(input);
+        let _ = KeySettings::from_slice(input);
+        let _ = Encryption::MACed(key.to_vec()).decrypt(input);
+        let _ = Encryption::Encrypted(key.to_vec()).decrypt(input);
+        let _ = Encryption::Encrypted(vec![0x00; 3]).decrypt(input);
+    }
+}
+
+#[test]
+pub fn encrypted_test() {
+    use block_modes::block_padding::ZeroPadding;
+    use block_modes::cipher::NewBlockCipher;
+    use block_modes::{BlockMode, Cbc};
+    use des::TdesEde2;
+    use generic_array::GenericArray;
+
+    let key = hex!("00 11 22 33 44 55 66 77 88 99 AA BB CC DD EE FF");
+    let encryption = Encryption::Encrypted(key.to_vec());
+
+    // The card appends the CRC, pads with zeros and enciphers with a plain TDES in CBC mode
+    let encipher = |data: &[u8]| {
+        let card: Cbc<TdesEde2, ZeroPadding> = Cbc::new(
+            TdesEde2::new(GenericArray::from_slice(&key)),
+            &Default::default(),
+        );
+        let mut vec = data.to_vec();
+        vec.extend(mifare_utils::crc_checksum(data));
+        card.encrypt_vec(&vec)
+    };
+
+    // Data and CRC may end in zero bytes, only the padding after the CRC is dropped
+    for length in 0..24 {
+        let mut data: Vec<u8> = (1..=length).collect();
+        assert_eq!(encryption.decrypt(&encipher(&data)).unwrap(), data);
+
+        if let Some(last) = data.last_mut() {
+            *last = 0x00;
+        }
+        assert_eq!(encryption.decrypt(&encipher(&data)).unwrap(), data);
+    }
+
+    let mut encrypted = encipher(&[0x01, 0x02, 0x03]);
+    encrypted[0] ^= 0x01;
+    assert!(encryption.decrypt(&encrypted).is_err());
+}
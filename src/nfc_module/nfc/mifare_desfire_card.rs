@@ -11,6 +11,7 @@ pub struct MiFareDESFireCard {
     pub card: NfcCard,
 }
 
+#[allow(clippy::empty_line_after_doc_comments)]
 impl MiFareDESFireCard {
     #[allow(clippy::match_like_matches_macro)]
     pub fn is_compatible(card: &NfcCard) -> bool {
@@ -26,7 +27,12 @@ impl MiFareDESFireCard {
         MiFareDESFireCard { card }
     }
 
-    fn transmit(&self, command: u8, data: &[u8]) -> NfcResult<(Status, Vec<u8>)> {
+    fn transmit(
+        &self,
+        command_name: &'static str,
+        command: u8,
+        data: &[u8],
+    ) -> NfcResult<(Status, Vec<u8>)> {
         // info!(
         //     "  Send Command: {:X?}, l={}, data={:X?}",
         //     command,
@@ -37,10 +43,10 @@ impl MiFareDESFireCard {
         let mut query = Vec::with_capacity(data.len() + 1);
         query.push(command);
         query.extend(data);
-        let mut data = self.card.transmit(&query)?;
+        let mut data = self.card.transmit(&query).command(command_name)?;
 
         if data.is_empty() {
-            return Err(NfcError::UnknownError);
+            return Err(NfcError::new(NfcErrorKind::CommunicationError).with_command(command_name));
         }
 
         let status = Status::parse(data.remove(0));
@@ -59,9 +65,12 @@ impl MiFareDESFireCard {
 
     #[allow(non_snake_case)]
     pub fn authenticate(&self, key_no: u8, key: &[u8]) -> NfcResult<Vec<u8>> {
-        let (status, ek_rndB) = self.transmit(0x0A, &[key_no])?;
+        let (status, ek_rndB) = self.transmit("authenticate_phase1", 0x0A, &[key_no])?;
         status.to_result("authenticate_phase1")?;
-        let rndB = mifare_utils::tdes_decrypt(key, &ek_rndB)?;
+        let rndB = mifare_utils::tdes_decrypt(key, &ek_rndB).command("authenticate")?;
+        if rndB.len() != 8 {
+            return Err(NfcError::new(NfcErrorKind::ByteParseError).with_command("authenticate"));
+        }
 
         let mut rndBshifted: Vec<u8> = Vec::with_capacity(8);
         rndBshifted.extend(&rndB[1..8]);
@@ -77,14 +86,17 @@ impl MiFareDESFireCard {
         rndA_rndBshifted.extend(&rndA);
         rndA_rndBshifted.extend(rndBshifted);
 
-        let dk_rndA_rndBshifted = mifare_utils::tdes_encrypt(key, &rndA_rndBshifted)?;
+        let dk_rndA_rndBshifted =
+            mifare_utils::tdes_encrypt(key, &rndA_rndBshifted).command("authenticate")?;
 
-        let (status, ek_rndAshifted_card) = self.transmit(0xAF, &dk_rndA_rndBshifted)?;
+        let (status, ek_rndAshifted_card) =
+            self.transmit("authenticate_phase2", 0xAF, &dk_rndA_rndBshifted)?;
         status.to_result("authenticate_phase2")?;
-        let rndAshifted_card = mifare_utils::tdes_decrypt(key, &ek_rndAshifted_card)?;
+        let rndAshifted_card =
+            mifare_utils::tdes_decrypt(key, &ek_rndAshifted_card).command("authenticate")?;
 
         if rndAshifted != rndAshifted_card {
-            return Err(NfcError::PermissionDenied);
+            return Err(NfcError::new(NfcErrorKind::PermissionDenied).with_command("authenticate"));
         }
 
         let mut session_key: Vec<u8> = Vec::with_capacity(16);
@@ -100,7 +112,7 @@ impl MiFareDESFireCard {
 
     #[allow(non_snake_case)]
     pub fn authenticate_phase1(&self, key_no: u8) -> NfcResult<Vec<u8>> {
-        let (status, ek_rndB) = self.transmit(0x0A, &[key_no])?;
+        let (status, ek_rndB) = self.transmit("authenticate_phase1", 0x0A, &[key_no])?;
         status.to_result("authenticate_phase1")?;
 
         Ok(ek_rndB)
@@ -108,7 +120,8 @@ impl MiFareDESFireCard {
 
     #[allow(non_snake_case)]
     pub fn authenticate_phase2(&self, dk_rndA_rndBshifted: &[u8]) -> NfcResult<Vec<u8>> {
-        let (status, ek_rndAshifted_card) = self.transmit(0xAF, dk_rndA_rndBshifted)?;
+        let (status, ek_rndAshifted_card) =
+            self.transmit("authenticate_phase2", 0xAF, dk_rndA_rndBshifted)?;
         status.to_result("authenticate_phase2")?;
 
         Ok(ek_rndAshifted_card)
@@ -118,15 +131,15 @@ impl MiFareDESFireCard {
         let s = settings.to_vec()?;
         let crc = mifare_utils::crc_checksum(&s);
         let data = [s[0], crc[0], crc[1], 0, 0, 0, 0, 0];
-        let data = mifare_utils::tdes_encrypt(session_key, &data)?;
+        let data = mifare_utils::tdes_encrypt(session_key, &data).command("change_key_settings")?;
 
-        let (status, _) = self.transmit(0x54, &data)?;
+        let (status, _) = self.transmit("change_key_settings", 0x54, &data)?;
 
         status.to_result("change_key_settings")
     }
 
     pub fn get_key_settings(&self) -> NfcResult<(KeySettings, u8)> {
-        let (status, result) = self.transmit(0x45, &[])?;
+        let (status, result) = self.transmit("get_key_settings", 0x45, &[])?;
         status.to_result("get_key_settings")?;
 
         let mut cursor = Cursor::new(result.as_slice());
@@ -149,7 +162,7 @@ impl MiFareDESFireCard {
             bytes.extend(new_key);
             bytes.extend(&mifare_utils::crc_checksum(new_key));
             bytes.extend(&[0, 0, 0, 0, 0, 0]);
-            mifare_utils::tdes_encrypt(session_key, &bytes)?
+            mifare_utils::tdes_encrypt(session_key, &bytes).command("change_key")?
         } else {
             let mut mix_key = [0u8; 16];
             for i in 0..16 {
@@ -162,19 +175,23 @@ impl MiFareDESFireCard {
             bytes.extend(&mifare_utils::crc_checksum(new_key));
             bytes.extend(&[0, 0, 0, 0]);
 
-            mifare_utils::tdes_encrypt(session_key, &bytes)?
+            mifare_utils::tdes_encrypt(session_key, &bytes).command("change_key")?
         };
 
         bytes.insert(0, key_no);
 
-        let (status, _) = self.transmit(0xC4, &bytes)?;
+        let (status, _) = self.transmit("change_key", 0xC4, &bytes)?;
 
         status.to_result("change_key")
     }
 
     pub fn get_key_version(&self, key_no: u8) -> NfcResult<u8> {
-        let (status, result) = self.transmit(0x64, &[key_no])?;
-        status.to_result_data(result[0], "get_key_version")
+        let (status, result) = self.transmit("get_key_version", 0x64, &[key_no])?;
+        status.to_result("get_key_version")?;
+
+        result.first().copied().ok_or_else(|| {
+            NfcError::new(NfcErrorKind::ByteParseError).with_command("get_key_version")
+        })
     }
 
     /**
@@ -188,6 +205,7 @@ impl MiFareDESFireCard {
         num_of_keys: u8,
     ) -> NfcResult<()> {
         let (status, _) = self.transmit(
+            "create_application",
             0xCA,
             &[aid[0], aid[1], aid[2], key_settings.to_byte()?, num_of_keys],
         )?;
@@ -196,16 +214,16 @@ impl MiFareDESFireCard {
     }
 
     pub fn delete_application(&self, aid: [u8; 3]) -> NfcResult<()> {
-        let (status, _) = self.transmit(0xDA, &aid)?;
+        let (status, _) = self.transmit("delete_application", 0xDA, &aid)?;
 
         status.to_result("delete_application")
     }
 
     pub fn get_application_ids(&self) -> NfcResult<Vec<[u8; 3]>> {
-        let (mut status, mut result) = self.transmit(0x6A, &[])?;
+        let (mut status, mut result) = self.transmit("get_application_ids", 0x6A, &[])?;
         status.to_result("get_application_ids")?;
         while status == Status::AdditionalFrame {
-            let (s, r) = self.transmit(STATUS_ADDITIONAL_FRAME, &[])?;
+            let (s, r) = self.transmit("get_application_ids+", STATUS_ADDITIONAL_FRAME, &[])?;
             status = s;
             status.to_result("get_application_ids+")?;
             result.extend(r);
@@ -224,22 +242,22 @@ impl MiFareDESFireCard {
     }
 
     pub fn select_application(&self, aid: [u8; 3]) -> NfcResult<()> {
-        let (status, _) = self.transmit(0x5A, &aid)?;
+        let (status, _) = self.transmit("select_application", 0x5A, &aid)?;
 
         status.to_result("select_application")
     }
 
     pub fn format_picc(&self) -> NfcResult<()> {
-        let (status, _) = self.transmit(0xFC, &[])?;
+        let (status, _) = self.transmit("format_picc", 0xFC, &[])?;
 
         status.to_result("format_picc")
     }
 
     pub fn get_version(&self) -> NfcResult<Version> {
-        let (mut status, mut result) = self.transmit(0x60, &[])?;
+        let (mut status, mut result) = self.transmit("get_version", 0x60, &[])?;
         status.to_result("get_version")?;
         while status == Status::AdditionalFrame {
-            let (s, r) = self.transmit(STATUS_ADDITIONAL_FRAME, &[])?;
+            let (s, r) = self.transmit("get_version+", STATUS_ADDITIONAL_FRAME, &[])?;
             status = s;
             status.to_result("get_version+")?;
             result.extend(r);
@@ -253,14 +271,14 @@ impl MiFareDESFireCard {
      */
 
     pub fn get_file_ids(&self) -> NfcResult<Vec<u8>> {
-        let (status, result) = self.transmit(0x6F, &[])?;
+        let (status, result) = self.transmit("get_file_ids", 0x6F, &[])?;
         status.to_result("get_file_ids")?;
 
         Ok(result)
     }
 
     pub fn get_file_settings(&self, file_no: u8) -> NfcResult<FileSettings> {
-        let (status, result) = self.transmit(0xF5, &[file_no])?;
+        let (status, result) = self.transmit("get_file_settings", 0xF5, &[file_no])?;
         status.to_result("get_file_settings")?;
 
         FileSettings::from_slice(&result)
@@ -280,12 +298,12 @@ impl MiFareDESFireCard {
 
         if let Some(key) = ciphered {
             bytes.extend(&mifare_utils::crc_checksum(&bytes));
-            bytes = mifare_utils::tdes_encrypt(&key, &bytes)?;
+            bytes = mifare_utils::tdes_encrypt(&key, &bytes).command("change_file_settings")?;
         }
 
         bytes.insert(0, file_no);
 
-        let (status, _) = self.transmit(0x5F, &bytes)?;
+        let (status, _) = self.transmit("change_file_settings", 0x5F, &bytes)?;
 
         status.to_result("change_file_settings")
     }
@@ -304,7 +322,7 @@ impl MiFareDESFireCard {
         access_rights.to_bytes(&mut bytes)?;
         bytes.write_u24::<LittleEndian>(file_size)?;
 
-        let (status, _) = self.transmit(0xCD, &bytes)?;
+        let (status, _) = self.transmit("create_std_data_file", 0xCD, &bytes)?;
 
         status.to_result("create_std_data_file")
     }
@@ -323,7 +341,7 @@ impl MiFareDESFireCard {
         access_rights.to_bytes(&mut bytes)?;
         bytes.write_u24::<LittleEndian>(file_size)?;
 
-        let (status, _) = self.transmit(0xCB, &bytes)?;
+        let (status, _) = self.transmit("create_backup_data_file", 0xCB, &bytes)?;
 
         status.to_result("create_backup_data_file")
     }
@@ -349,7 +367,7 @@ impl MiFareDESFireCard {
         bytes.write_u32::<LittleEndian>(limited_credit_value)?;
         bytes.write_u8(if limited_credit_enabled { 0x01 } else { 0x00 })?;
 
-        let (status, _) = self.transmit(0xCC, &bytes)?;
+        let (status, _) = self.transmit("create_value_file", 0xCC, &bytes)?;
 
         status.to_result("create_value_file")
     }
@@ -370,7 +388,7 @@ impl MiFareDESFireCard {
         bytes.write_u24::<LittleEndian>(record_size)?;
         bytes.write_u24::<LittleEndian>(max_no_of_keys)?;
 
-        let (status, _) = self.transmit(0xC1, &bytes)?;
+        let (status, _) = self.transmit("create_linear_record_file", 0xC1, &bytes)?;
 
         status.to_result("create_linear_record_file")
     }
@@ -391,7 +409,7 @@ impl MiFareDESFireCard {
         bytes.write_u24::<LittleEndian>(record_size)?;
         bytes.write_u24::<LittleEndian>(max_no_of_keys)?;
 
-        let (status, _) = self.transmit(0xC0, &bytes)?;
+        let (status, _) = self.transmit("create_cyclic_record_file", 0xC0, &bytes)?;
 
         status.to_result("create_cyclic_record_file")
     }
@@ -401,7 +419,7 @@ impl MiFareDESFireCard {
 
         bytes.write_u8(file_no)?;
 
-        let (status, _) = self.transmit(0xDF, &bytes)?;
+        let (status, _) = self.transmit("delete_file", 0xDF, &bytes)?;
 
         status.to_result("delete_file")
     }
@@ -419,16 +437,16 @@ impl MiFareDESFireCard {
         bytes.write_u24::<LittleEndian>(offset)?;
         bytes.write_u24::<LittleEndian>(length)?;
 
-        let (mut status, mut result) = self.transmit(0xBD, &bytes)?;
+        let (mut status, mut result) = self.transmit("read_data", 0xBD, &bytes)?;
         status.to_result("read_data")?;
         while status == Status::AdditionalFrame {
-            let (s, r) = self.transmit(STATUS_ADDITIONAL_FRAME, &[])?;
+            let (s, r) = self.transmit("read_data+", STATUS_ADDITIONAL_FRAME, &[])?;
             status = s;
             status.to_result("read_data+")?;
             result.extend(r);
         }
 
-        encryption.decrypt(&result)
+        encryption.decrypt(&result).command("read_data")
     }
 
     pub fn write_data(
@@ -440,7 +458,7 @@ impl MiFareDESFireCard {
     ) -> NfcResult<()> {
         let mut bytes: Vec<u8> = Vec::new();
 
-        let d = encryption.encrypt(data)?;
+        let d = encryption.encrypt(data).command("write_data")?;
 
         bytes.write_u8(file_no)?;
         bytes.write_u24::<LittleEndian>(offset)?;
@@ -451,14 +469,18 @@ impl MiFareDESFireCard {
         bytes.extend(&d[0..length]);
         offset += length;
 
-        let (mut status, _) = self.transmit(0x3D, &bytes)?;
+        let (mut status, _) = self.transmit("write_data", 0x3D, &bytes)?;
         status.to_result("write_data")?;
         while status == Status::AdditionalFrame {
             let length = std::cmp::min(d.len() - offset, 59);
             if length == 0 {
                 break;
             }
-            let (s, r) = self.transmit(STATUS_ADDITIONAL_FRAME, &d[offset..(offset + length)])?;
+            let (s, r) = self.transmit(
+                "write_data+",
+                STATUS_ADDITIONAL_FRAME,
+                &d[offset..(offset + length)],
+            )?;
             status = s;
             status.to_result("write_data+")?;
             offset += length;
@@ -472,10 +494,10 @@ impl MiFareDESFireCard {
 
         bytes.write_u8(file_no)?;
 
-        let (status, result) = self.transmit(0x6C, &bytes)?;
+        let (status, result) = self.transmit("get_value", 0x6C, &bytes)?;
         status.to_result("get_value")?;
 
-        let result = encryption.decrypt(&result)?;
+        let result = encryption.decrypt(&result).command("get_value")?;
 
         let mut cursor = Cursor::new(result.as_slice());
         let value = cursor.read_u32::<LittleEndian>()?;
@@ -490,11 +512,11 @@ impl MiFareDESFireCard {
 
         let mut data: Vec<u8> = Vec::new();
         data.write_u32::<LittleEndian>(value)?;
-        data = encryption.encrypt(&data)?;
+        data = encryption.encrypt(&data).command("credit")?;
 
         bytes.extend(&data);
 
-        let (status, _) = self.transmit(0x0C, &bytes)?;
+        let (status, _) = self.transmit("credit", 0x0C, &bytes)?;
 
         status.to_result("credit")
     }
@@ -506,11 +528,11 @@ impl MiFareDESFireCard {
 
         let mut data: Vec<u8> = Vec::new();
         data.write_u32::<LittleEndian>(value)?;
-        data = encryption.encrypt(&data)?;
+        data = encryption.encrypt(&data).command("debit")?;
 
         bytes.extend(&data);
 
-        let (status, _) = self.transmit(0xDC, &bytes)?;
+        let (status, _) = self.transmit("debit", 0xDC, &bytes)?;
 
         status.to_result("debit")
     }
@@ -522,11 +544,11 @@ impl MiFareDESFireCard {
 
         let mut data: Vec<u8> = Vec::new();
         data.write_u32::<LittleEndian>(value)?;
-        data = encryption.encrypt(&data)?;
+        data = encryption.encrypt(&data).command("limited_credit")?;
 
         bytes.extend(&data);
 
-        let (status, _) = self.transmit(0x1C, &bytes)?;
+        let (status, _) = self.transmit("limited_credit", 0x1C, &bytes)?;
 
         status.to_result("limited_credit")
     }
@@ -540,25 +562,29 @@ impl MiFareDESFireCard {
     ) -> NfcResult<()> {
         let mut bytes: Vec<u8> = Vec::new();
 
-        let d = encryption.encrypt(data)?;
+        let d = encryption.encrypt(data).command("write_record")?;
 
         bytes.write_u8(file_no)?;
         bytes.write_u24::<LittleEndian>(offset)?;
-        bytes.write_u24::<LittleEndian>(d.len() as u32)?;
+        bytes.write_u24::<LittleEndian>(data.len() as u32)?;
 
         let mut offset = 0;
         let length = std::cmp::min(d.len(), 52);
         bytes.extend(&d[0..length]);
         offset += length;
 
-        let (mut status, _) = self.transmit(0x3B, &bytes)?;
+        let (mut status, _) = self.transmit("write_record", 0x3B, &bytes)?;
         status.to_result("write_record")?;
         while status == Status::AdditionalFrame {
             let length = std::cmp::min(d.len() - offset, 59);
             if length == 0 {
                 break;
             }
-            let (s, r) = self.transmit(STATUS_ADDITIONAL_FRAME, &d[offset..(offset + length)])?;
+            let (s, r) = self.transmit(
+                "write_record+",
+                STATUS_ADDITIONAL_FRAME,
+                &d[offset..(offset + length)],
+            )?;
             offset += length;
             status = s;
             status.to_result("write_record+")?;
@@ -580,16 +606,16 @@ impl MiFareDESFireCard {
         bytes.write_u24::<LittleEndian>(offset)?;
         bytes.write_u24::<LittleEndian>(length)?;
 
-        let (mut status, mut result) = self.transmit(0xBB, &bytes)?;
+        let (mut status, mut result) = self.transmit("read_record", 0xBB, &bytes)?;
         status.to_result("read_record")?;
         while status == Status::AdditionalFrame {
-            let (s, r) = self.transmit(STATUS_ADDITIONAL_FRAME, &[])?;
+            let (s, r) = self.transmit("read_record+", STATUS_ADDITIONAL_FRAME, &[])?;
             status = s;
             status.to_result("read_record+")?;
             result.extend(r);
         }
 
-        encryption.decrypt(&result)
+        encryption.decrypt(&result).command("read_record")
     }
 
     pub fn clear_record_file(&self, file_no: u8) -> NfcResult<()> {
@@ -597,19 +623,19 @@ impl MiFareDESFireCard {
 
         bytes.write_u8(file_no)?;
 
-        let (status, _) = self.transmit(0xEB, &bytes)?;
+        let (status, _) = self.transmit("clear_record_file", 0xEB, &bytes)?;
 
         status.to_result("clear_record_file")
     }
 
     pub fn commit_transaction(&self) -> NfcResult<()> {
-        let (status, _) = self.transmit(0xC7, &[])?;
+        let (status, _) = self.transmit("commit_transaction", 0xC7, &[])?;
 
         status.to_result("commit_transaction")
     }
 
     pub fn abort_transaction(&self) -> NfcResult<()> {
-        let (status, _) = self.transmit(0xA7, &[])?;
+        let (status, _) = self.transmit("abort_transaction", 0xA7, &[])?;
 
         status.to_result("abort_transaction")
     }
@@ -620,3 +646,94 @@ impl From<MiFareDESFireCard> for NfcCard {
         card.card
     }
 }
+
+#[test]
+pub fn write_record_test() {
+    use std::sync::{Arc, Mutex};
+
+    use super::CardTransport;
+
+    /// Accepts every command and keeps the queries.
+    struct RecordingCard(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl CardTransport for RecordingCard {
+        fn transmit(&self, query: &[u8]) -> NfcResult<Vec<u8>> {
+            self.0.lock().unwrap().push(query.to_vec());
+            Ok(vec![Status::OperationOk.code()])
+        }
+
+        fn get_attribute(&self, _attribute: pcsc::Attribute) -> NfcResult<Vec<u8>> {
+            Ok(Vec::new())
+        }
+
+        fn reconnect(&mut self) -> NfcResult<()> {
+            Ok(())
+        }
+
+        fn disconnect(self: Box<Self>) -> NfcResult<()> {
+            Ok(())
+        }
+    }
+
+    let queries = Arc::new(Mutex::new(Vec::new()));
+    let card = MiFareDESFireCard::new(NfcCard::new(RecordingCard(queries.clone())));
+    let key = hex!("00 11 22 33 44 55 66 77 88 99 AA BB CC DD EE FF");
+    let data = hex!("01 02 03 04 05");
+
+    // The length names the plain data, not the enciphered data with CRC and padding
+    card.write_record(1, 0, &data, Encryption::Encrypted(key.to_vec()))
+        .unwrap();
+    card.write_data(1, 0, &data, Encryption::Encrypted(key.to_vec()))
+        .unwrap();
+    card.write_record(1, 0, &data, Encryption::MACed(key.to_vec()))
+        .unwrap();
+
+    let queries = queries.lock().unwrap();
+    assert_eq!(queries.len(), 3);
+    assert_eq!(queries[0][..8], hex!("3B 01 00 00 00 05 00 00"));
+    assert_eq!(queries[0].len(), 8 + 8);
+    assert_eq!(queries[1][..8], hex!("3D 01 00 00 00 05 00 00"));
+    assert_eq!(queries[2][..8], hex!("3B 01 00 00 00 05 00 00"));
+    assert_eq!(queries[2].len(), 8 + 5 + 4);
+}
+
+#[test]
+pub fn transmit_error_test() {
+    use super::CardTransport;
+
+    /// Card that was pulled (`true`) or answers without a status byte (`false`).
+    struct BrokenCard(bool);
+
+    impl CardTransport for BrokenCard {
+        fn transmit(&self, _query: &[u8]) -> NfcResult<Vec<u8>> {
+            if self.0 {
+                Err(NfcErrorKind::CommunicationError.into())
+            } else {
+                Ok(Vec::new())
+            }
+        }
+
+        fn get_attribute(&self, _attribute: pcsc::Attribute) -> NfcResult<Vec<u8>> {
+            Ok(Vec::new())
+        }
+
+        fn reconnect(&mut self) -> NfcResult<()> {
+            Ok(())
+        }
+
+        fn disconnect(self: Box<Self>) -> NfcResult<()> {
+            Ok(())
+        }
+    }
+
+    let key = hex!("00 11 22 33 44 55 66 77 88 99 AA BB CC DD EE FF");
+    for pulled in [true, false] {
+        let card = MiFareDESFireCard::new(NfcCard::new(BrokenCard(pulled)));
+
+        let error = card.authenticate(0, &key).unwrap_err();
+        assert_eq!(error.kind, NfcErrorKind::CommunicationError);
+        assert_eq!(error.command, Some("authenticate_phase1"));
+        let error = card.get_version().unwrap_err();
+        assert_eq!(error.command, Some("get_version"));
+    }
+}
@@ -0,0 +1,1317 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Cursor;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use block_modes::cipher::{BlockEncrypt, NewBlockCipher};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use des::TdesEde2;
+use generic_array::GenericArray;
+
+use super::mifare_desfire::{Status, STATUS_ADDITIONAL_FRAME};
+use super::mifare_utils;
+use super::{CardTransport, NfcResult};
+
+const ATR: [u8; 6] = hex!("3B 81 80 01 80 80");
+const UID: [u8; 7] = hex!("04 A5 C8 2A 96 5D 80");
+const PICC_APPLICATION: [u8; 3] = hex!("00 00 00");
+const DEFAULT_KEY: [u8; 16] = [0; 16];
+
+/// Longer responses are split into additional frames of this size.
+const MAX_FRAME_SIZE: usize = 59;
+const MAX_APPLICATIONS: usize = 28;
+const MAX_FILE_NUMBER: u8 = 31;
+const MAX_KEYS: u8 = 14;
+
+/// Access right nibble that grants access without authentication.
+const ACCESS_FREE: u8 = 0xE;
+/// Access right nibble that denies any access.
+const ACCESS_DENIED: u8 = 0xF;
+
+const FILE_TYPE_STD_DATA: u8 = 0x00;
+const FILE_TYPE_BACKUP_DATA: u8 = 0x01;
+const FILE_TYPE_VALUE: u8 = 0x02;
+const FILE_TYPE_LINEAR_RECORD: u8 = 0x03;
+const FILE_TYPE_CYCLIC_RECORD: u8 = 0x04;
+
+type CommandResult = Result<(Status, Vec<u8>), Status>;
+
+/// MIFARE DESFire EV1 emulated in software, for tests and the simulation mode.
+///
+/// Speaks the native command set with legacy DES/2K3DES authentication and keeps
+/// real application, file and key state. A clone is the same card put on the
+/// reader again: it shares the memory but starts a new session.
+pub struct MiFareDESFireSimulationCard {
+    picc: Arc<Mutex<Picc>>,
+    session: Mutex<Session>,
+}
+
+impl MiFareDESFireSimulationCard {
+    /// Blank card, only the PICC master key is set to the all zero default key.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let mut applications = BTreeMap::new();
+        applications.insert(PICC_APPLICATION, Application::new(0x0F, 1));
+
+        Self {
+            picc: Arc::new(Mutex::new(Picc { applications })),
+            session: Mutex::new(Session::default()),
+        }
+    }
+}
+
+impl Clone for MiFareDESFireSimulationCard {
+    fn clone(&self) -> Self {
+        Self {
+            picc: self.picc.clone(),
+            session: Mutex::new(Session::default()),
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl CardTransport for MiFareDESFireSimulationCard {
+    fn transmit(&self, query: &[u8]) -> NfcResult<Vec<u8>> {
+        let mut session = lock(&self.session);
+        let mut picc = lock(&self.picc);
+
+        let result = match query.split_first() {
+            Some((&STATUS_ADDITIONAL_FRAME, data)) => session.continue_frame(&mut picc, data),
+            Some((&command, data)) => {
+                // Any other command cancels a chained command.
+                session.pending = Pending::None;
+                session.execute(&mut picc, command, data)
+            }
+            None => Err(Status::LengthError),
+        };
+
+        let (status, data) = result.unwrap_or_else(|status| (status, Vec::new()));
+        let mut response = Vec::with_capacity(data.len() + 1);
+        response.push(status.code());
+        response.extend(data);
+        Ok(response)
+    }
+
+    fn get_attribute(&self, attribute: pcsc::Attribute) -> NfcResult<Vec<u8>> {
+        match attribute {
+            pcsc::Attribute::AtrString => Ok(ATR.into()),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn reconnect(&mut self) -> NfcResult<()> {
+        *lock(&self.session) = Session::default();
+        Ok(())
+    }
+
+    fn disconnect(self: Box<Self>) -> NfcResult<()> {
+        Ok(())
+    }
+}
+
+struct Picc {
+    /// All applications including the PICC level itself.
+    applications: BTreeMap<[u8; 3], Application>,
+}
+
+struct Application {
+    key_settings: u8,
+    keys: Vec<[u8; 16]>,
+    files: BTreeMap<u8, File>,
+}
+
+impl Application {
+    fn new(key_settings: u8, no_of_keys: u8) -> Self {
+        Self {
+            key_settings,
+            keys: vec![DEFAULT_KEY; no_of_keys as usize],
+            files: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct File {
+    communication: u8,
+    /// Access rights as sent on the wire: `RW | Change`, `Read | Write`.
+    access_rights: [u8; 2],
+    content: FileContent,
+}
+
+impl File {
+    fn read_key(&self) -> u8 {
+        self.access_rights[1] >> 4
+    }
+
+    fn write_key(&self) -> u8 {
+        self.access_rights[1] & 0x0F
+    }
+
+    fn read_write_key(&self) -> u8 {
+        self.access_rights[0] >> 4
+    }
+
+    fn change_key(&self) -> u8 {
+        self.access_rights[0] & 0x0F
+    }
+}
+
+#[derive(Clone)]
+enum FileContent {
+    Data {
+        backup: bool,
+        data: Vec<u8>,
+    },
+    Value {
+        value: i32,
+        lower_limit: i32,
+        upper_limit: i32,
+        limited_credit_value: i32,
+        limited_credit_enabled: bool,
+    },
+    Record {
+        cyclic: bool,
+        record_size: usize,
+        max_no_records: usize,
+        records: Vec<Vec<u8>>,
+    },
+}
+
+/// Communication mode of a file access, with the session key if it is secured.
+enum Communication {
+    Plain,
+    Maced(Vec<u8>),
+    Enciphered(Vec<u8>),
+}
+
+impl Communication {
+    /// Size of `length` bytes of data on the wire.
+    fn encoded_len(&self, length: usize) -> usize {
+        match self {
+            Communication::Plain => length,
+            Communication::Maced(_) => length + 4,
+            Communication::Enciphered(_) => (length + 2).div_ceil(8) * 8,
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, Status> {
+        Ok(match self {
+            Communication::Plain => data.to_vec(),
+            Communication::Maced(key) => {
+                let mut encoded = data.to_vec();
+                encoded.extend(mac(key, data)?);
+                encoded
+            }
+            Communication::Enciphered(key) => {
+                let mut encoded = data.to_vec();
+                encoded.extend(mifare_utils::crc_checksum(data));
+                encipher(key, &encoded)?
+            }
+        })
+    }
+
+    fn decode(&self, data: &[u8], length: usize) -> Result<Vec<u8>, Status> {
+        if data.len() != self.encoded_len(length) {
+            return Err(Status::LengthError);
+        }
+
+        match self {
+            Communication::Plain => Ok(data.to_vec()),
+            Communication::Maced(key) => {
+                let (data, received) = data.split_at(length);
+                if mac(key, data)? != received {
+                    return Err(Status::IntegrityError);
+                }
+                Ok(data.to_vec())
+            }
+            Communication::Enciphered(key) => {
+                let deciphered = decipher(key, data)?;
+                let (data, rest) = deciphered.split_at(length);
+                if rest[0..2] != mifare_utils::crc_checksum(data) {
+                    return Err(Status::IntegrityError);
+                }
+                Ok(data.to_vec())
+            }
+        }
+    }
+}
+
+fn cipher(key: &[u8]) -> Result<TdesEde2, Status> {
+    let key = mifare_utils::tdes_key(key).map_err(|_| Status::IntegrityError)?;
+    Ok(TdesEde2::new(GenericArray::from_slice(&key)))
+}
+
+/// Enciphers a response in CBC mode with a zero IV, like the card does.
+fn encipher(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Status> {
+    let cipher = cipher(key)?;
+
+    let mut data = data.to_vec();
+    data.resize(data.len().div_ceil(8) * 8, 0);
+
+    let mut previous = [0u8; 8];
+    for block in data.chunks_mut(8) {
+        block.iter_mut().zip(previous).for_each(|(b, p)| *b ^= p);
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        previous.copy_from_slice(block);
+    }
+
+    Ok(data)
+}
+
+/// Deciphers data of the reader, which enciphers with the DES decryption (legacy send mode).
+fn decipher(key: &[u8], data: &[u8]) -> Result<Vec<u8>, Status> {
+    if !data.len().is_multiple_of(8) {
+        return Err(Status::LengthError);
+    }
+    let cipher = cipher(key)?;
+
+    let mut data = data.to_vec();
+    let mut previous = [0u8; 8];
+    for block in data.chunks_mut(8) {
+        let received: [u8; 8] = block.try_into().expect("Blocks have 8 bytes");
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        block.iter_mut().zip(previous).for_each(|(b, p)| *b ^= p);
+        previous = received;
+    }
+
+    Ok(data)
+}
+
+fn mac(key: &[u8], data: &[u8]) -> Result<[u8; 4], Status> {
+    if data.is_empty() {
+        return Ok([0; 4]);
+    }
+    mifare_utils::mac(key, data).map_err(|_| Status::IntegrityError)
+}
+
+/// Legacy DES keys carry their version in the parity bits of the first half.
+fn key_version(key: &[u8; 16]) -> u8 {
+    key[0..8]
+        .iter()
+        .fold(0, |version, byte| (version << 1) | (byte & 0x01))
+}
+
+fn rotate_left(value: &[u8]) -> Vec<u8> {
+    let mut rotated = value[1..].to_vec();
+    rotated.push(value[0]);
+    rotated
+}
+
+fn length_error(_: std::io::Error) -> Status {
+    Status::LengthError
+}
+
+fn expect_len(data: &[u8], length: usize) -> Result<(), Status> {
+    if data.len() == length {
+        Ok(())
+    } else {
+        Err(Status::LengthError)
+    }
+}
+
+fn ok(data: Vec<u8>) -> CommandResult {
+    Ok((Status::OperationOk, data))
+}
+
+/// Second half of a command that spans several frames.
+enum Pending {
+    None,
+    Authenticate {
+        key_no: u8,
+        key: [u8; 16],
+        rnd_b: [u8; 8],
+    },
+    Response(VecDeque<Vec<u8>>),
+    Write {
+        file_no: u8,
+        offset: usize,
+        length: usize,
+        communication: Communication,
+        data: Vec<u8>,
+    },
+}
+
+/// Changes to backup, value and record files until the transaction is committed.
+#[derive(Default)]
+struct Transaction {
+    files: BTreeMap<u8, FileContent>,
+    /// Sum of all debits per value file, it becomes the limited credit value.
+    debits: BTreeMap<u8, i32>,
+}
+
+struct Session {
+    aid: [u8; 3],
+    /// Authenticated key number and session key.
+    auth: Option<(u8, Vec<u8>)>,
+    transaction: Transaction,
+    pending: Pending,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            aid: PICC_APPLICATION,
+            auth: None,
+            transaction: Transaction::default(),
+            pending: Pending::None,
+        }
+    }
+}
+
+impl Session {
+    fn execute(&mut self, picc: &mut Picc, command: u8, data: &[u8]) -> CommandResult {
+        match command {
+            0x0A => self.authenticate(picc, data),
+            0x54 => self.change_key_settings(picc, data),
+            0x45 => self.get_key_settings(picc, data),
+            0xC4 => self.change_key(picc, data),
+            0x64 => self.get_key_version(picc, data),
+            0xCA => self.create_application(picc, data),
+            0xDA => self.delete_application(picc, data),
+            0x6A => self.get_application_ids(picc, data),
+            0x5A => self.select_application(picc, data),
+            0xFC => self.format_picc(picc, data),
+            0x60 => self.get_version(data),
+            0x6F => self.get_file_ids(picc, data),
+            0xF5 => self.get_file_settings(picc, data),
+            0x5F => self.change_file_settings(picc, data),
+            0xCD | 0xCB | 0xCC | 0xC1 | 0xC0 => self.create_file(picc, command, data),
+            0xDF => self.delete_file(picc, data),
+            0xBD => self.read_data(picc, data),
+            0x3D | 0x3B => self.write(picc, command, data),
+            0x6C => self.get_value(picc, data),
+            0x0C | 0xDC | 0x1C => self.change_value(picc, command, data),
+            0xBB => self.read_records(picc, data),
+            0xEB => self.clear_record_file(picc, data),
+            0xC7 => self.commit_transaction(picc, data),
+            0xA7 => self.abort_transaction(data),
+            _ => Err(Status::IllegalCommandCode),
+        }
+    }
+
+    fn continue_frame(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        match std::mem::replace(&mut self.pending, Pending::None) {
+            Pending::None => Err(Status::IllegalCommandCode),
+            Pending::Authenticate { key_no, key, rnd_b } => {
+                expect_len(data, 16)?;
+                let rnd_a_rnd_b = decipher(&key, data)?;
+                if rnd_a_rnd_b[8..16] != rotate_left(&rnd_b) {
+                    return Err(Status::AuthenticationError);
+                }
+                let rnd_a = &rnd_a_rnd_b[0..8];
+
+                let mut session_key = Vec::with_capacity(16);
+                session_key.extend(&rnd_a[0..4]);
+                session_key.extend(&rnd_b[0..4]);
+                if mifare_utils::is_key_2des(&key) {
+                    session_key.extend(&rnd_a[4..8]);
+                    session_key.extend(&rnd_b[4..8]);
+                }
+                self.auth = Some((key_no, session_key));
+
+                ok(encipher(&key, &rotate_left(rnd_a))?)
+            }
+            Pending::Response(mut frames) => {
+                let frame = frames.pop_front().unwrap_or_default();
+                Ok(self.chain(frame, frames))
+            }
+            Pending::Write {
+                file_no,
+                offset,
+                length,
+                communication,
+                data: mut received,
+            } => {
+                received.extend(data);
+                let expected = communication.encoded_len(length);
+                if received.len() > expected {
+                    return Err(Status::LengthError);
+                }
+                if received.len() < expected {
+                    self.pending = Pending::Write {
+                        file_no,
+                        offset,
+                        length,
+                        communication,
+                        data: received,
+                    };
+                    return Ok((Status::AdditionalFrame, Vec::new()));
+                }
+
+                let data = communication.decode(&received, length)?;
+                self.apply_write(picc, file_no, offset, &data)
+            }
+        }
+    }
+
+    /// Sends `frame` and keeps the remaining frames for the next additional frame request.
+    fn chain(&mut self, frame: Vec<u8>, frames: VecDeque<Vec<u8>>) -> (Status, Vec<u8>) {
+        if frames.is_empty() {
+            (Status::OperationOk, frame)
+        } else {
+            self.pending = Pending::Response(frames);
+            (Status::AdditionalFrame, frame)
+        }
+    }
+
+    fn respond(&mut self, data: Vec<u8>, frame_size: usize) -> CommandResult {
+        let mut frames: VecDeque<Vec<u8>> = data
+            .chunks(frame_size)
+            .map(|frame| frame.to_vec())
+            .collect();
+        let frame = frames.pop_front().unwrap_or_default();
+        Ok(self.chain(frame, frames))
+    }
+
+    fn application<'a>(&self, picc: &'a mut Picc) -> Result<&'a mut Application, Status> {
+        picc.applications
+            .get_mut(&self.aid)
+            .ok_or(Status::ApplicationNotFound)
+    }
+
+    fn is_master(&self) -> bool {
+        matches!(self.auth, Some((0, _)))
+    }
+
+    /// Checks a key settings flag that can waive the master key authentication.
+    fn require_master_unless(&self, key_settings: u8, flag: u8) -> Result<(), Status> {
+        match self.auth {
+            _ if key_settings & flag != 0 => Ok(()),
+            Some((0, _)) => Ok(()),
+            Some(_) => Err(Status::PermissionDenied),
+            None => Err(Status::AuthenticationError),
+        }
+    }
+
+    /// Grants access if one of the given access right keys is free or authenticated.
+    fn access(&self, file: &File, keys: &[u8]) -> Result<Communication, Status> {
+        if keys.contains(&ACCESS_FREE) {
+            return Ok(Communication::Plain);
+        }
+
+        let (key_no, session_key) = self.auth.as_ref().ok_or(Status::AuthenticationError)?;
+        if !keys.contains(key_no) {
+            return Err(Status::PermissionDenied);
+        }
+
+        Ok(match file.communication & 0x03 {
+            0x01 => Communication::Maced(session_key.clone()),
+            0x03 => Communication::Enciphered(session_key.clone()),
+            _ => Communication::Plain,
+        })
+    }
+
+    fn file<'a>(&self, picc: &'a mut Picc, file_no: u8) -> Result<&'a mut File, Status> {
+        self.application(picc)?
+            .files
+            .get_mut(&file_no)
+            .ok_or(Status::FileNotFound)
+    }
+
+    /// Content of a file as changed by the running transaction.
+    fn staged<'a>(
+        transaction: &'a mut Transaction,
+        file: &File,
+        file_no: u8,
+    ) -> &'a mut FileContent {
+        transaction
+            .files
+            .entry(file_no)
+            .or_insert_with(|| file.content.clone())
+    }
+
+    fn abort(&mut self) {
+        self.transaction = Transaction::default();
+    }
+
+    /*
+     * Command Set - Security Related Commands
+     */
+
+    fn authenticate(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 1)?;
+        self.auth = None;
+        self.abort();
+
+        let key_no = data[0];
+        let key = *self
+            .application(picc)?
+            .keys
+            .get(key_no as usize)
+            .ok_or(Status::NoSuchKey)?;
+
+        let rnd_b = mifare_utils::generate_key::<8>();
+        self.pending = Pending::Authenticate { key_no, key, rnd_b };
+
+        Ok((Status::AdditionalFrame, encipher(&key, &rnd_b)?))
+    }
+
+    fn change_key_settings(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 8)?;
+        let session_key = match self.auth {
+            Some((0, ref session_key)) => session_key.clone(),
+            Some(_) => return Err(Status::PermissionDenied),
+            None => return Err(Status::AuthenticationError),
+        };
+
+        let application = self.application(picc)?;
+        if application.key_settings & 0x08 == 0 {
+            return Err(Status::PermissionDenied);
+        }
+
+        let data = decipher(&session_key, data)?;
+        if data[1..3] != mifare_utils::crc_checksum(&data[0..1]) {
+            return Err(Status::IntegrityError);
+        }
+        application.key_settings = data[0];
+
+        ok(Vec::new())
+    }
+
+    fn get_key_settings(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 0)?;
+        let application = self.application(picc)?;
+        self.require_master_unless(application.key_settings, 0x02)?;
+
+        ok(vec![application.key_settings, application.keys.len() as u8])
+    }
+
+    fn change_key(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 25)?;
+        let (auth_key_no, session_key) = self.auth.clone().ok_or(Status::AuthenticationError)?;
+        let is_picc = self.aid == PICC_APPLICATION;
+        let application = self.application(picc)?;
+
+        let key_no = data[0];
+        let old_key = *application
+            .keys
+            .get(key_no as usize)
+            .ok_or(Status::NoSuchKey)?;
+
+        let allowed = match (key_no, application.key_settings >> 4) {
+            (0, _) => auth_key_no == 0 && application.key_settings & 0x01 != 0,
+            _ if is_picc => false,
+            (_, ACCESS_FREE) => auth_key_no == key_no,
+            (_, ACCESS_DENIED) => false,
+            (_, change_key) => auth_key_no == change_key,
+        };
+        if !allowed {
+            return Err(Status::PermissionDenied);
+        }
+
+        let data = decipher(&session_key, &data[1..])?;
+        let mut new_key = [0u8; 16];
+        if key_no == auth_key_no {
+            new_key.copy_from_slice(&data[0..16]);
+            if data[16..18] != mifare_utils::crc_checksum(&new_key) {
+                return Err(Status::IntegrityError);
+            }
+        } else {
+            for (i, byte) in new_key.iter_mut().enumerate() {
+                *byte = data[i] ^ old_key[i];
+            }
+            if data[16..18] != mifare_utils::crc_checksum(&data[0..16])
+                || data[18..20] != mifare_utils::crc_checksum(&new_key)
+            {
+                return Err(Status::IntegrityError);
+            }
+        }
+        application.keys[key_no as usize] = new_key;
+
+        // The session of a changed key ends.
+        if key_no == auth_key_no {
+            self.auth = None;
+        }
+
+        ok(Vec::new())
+    }
+
+    fn get_key_version(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 1)?;
+        let key = self
+            .application(picc)?
+            .keys
+            .get(data[0] as usize)
+            .ok_or(Status::NoSuchKey)?;
+
+        ok(vec![key_version(key)])
+    }
+
+    /*
+     * Command Set - PICC Level Commands
+     */
+
+    fn picc_application<'a>(&self, picc: &'a mut Picc) -> Result<&'a mut Application, Status> {
+        if self.aid != PICC_APPLICATION {
+            return Err(Status::PermissionDenied);
+        }
+        self.application(picc)
+    }
+
+    fn create_application(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 5)?;
+        let key_settings = self.picc_application(picc)?.key_settings;
+        self.require_master_unless(key_settings, 0x04)?;
+
+        let aid = [data[0], data[1], data[2]];
+        let no_of_keys = data[4];
+        // Only DES and 2K3DES keys are supported.
+        if aid == PICC_APPLICATION || no_of_keys == 0 || no_of_keys > MAX_KEYS {
+            return Err(Status::ParameterError);
+        }
+        if picc.applications.contains_key(&aid) {
+            return Err(Status::DuplicateError);
+        }
+        if picc.applications.len() > MAX_APPLICATIONS {
+            return Err(Status::CountError);
+        }
+
+        picc.applications
+            .insert(aid, Application::new(data[3], no_of_keys));
+
+        ok(Vec::new())
+    }
+
+    fn delete_application(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 3)?;
+        let aid = [data[0], data[1], data[2]];
+        if aid == PICC_APPLICATION {
+            return Err(Status::ParameterError);
+        }
+        if !picc.applications.contains_key(&aid) {
+            return Err(Status::ApplicationNotFound);
+        }
+
+        // Either the PICC master key or the master key of the application itself.
+        if self.aid != aid {
+            let key_settings = self.picc_application(picc)?.key_settings;
+            self.require_master_unless(key_settings, 0x04)?;
+        } else if !self.is_master() {
+            return Err(Status::PermissionDenied);
+        }
+
+        picc.applications.remove(&aid);
+        if self.aid == aid {
+            *self = Session::default();
+        }
+
+        ok(Vec::new())
+    }
+
+    fn get_application_ids(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 0)?;
+        let key_settings = self.picc_application(picc)?.key_settings;
+        self.require_master_unless(key_settings, 0x02)?;
+
+        let aids: Vec<u8> = picc
+            .applications
+            .keys()
+            .filter(|aid| **aid != PICC_APPLICATION)
+            .flatten()
+            .copied()
+            .collect();
+
+        // A frame holds up to 19 application ids.
+        self.respond(aids, 19 * 3)
+    }
+
+    fn select_application(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 3)?;
+        let aid = [data[0], data[1], data[2]];
+        if !picc.applications.contains_key(&aid) {
+            return Err(Status::ApplicationNotFound);
+        }
+
+        *self = Session {
+            aid,
+            ..Session::default()
+        };
+
+        ok(Vec::new())
+    }
+
+    fn format_picc(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 0)?;
+        self.picc_application(picc)?;
+        if !self.is_master() {
+            return Err(Status::PermissionDenied);
+        }
+
+        picc.applications.retain(|aid, _| *aid == PICC_APPLICATION);
+
+        ok(Vec::new())
+    }
+
+    fn get_version(&mut self, data: &[u8]) -> CommandResult {
+        expect_len(data, 0)?;
+
+        let hardware = hex!("04 01 01 01 00 18 05").to_vec();
+        let software = hex!("04 01 01 01 04 18 05").to_vec();
+        let mut production = UID.to_vec();
+        production.extend(hex!("BA 5E BA 11 00 2A 13"));
+
+        Ok(self.chain(hardware, VecDeque::from([software, production])))
+    }
+
+    /*
+     * Command Set - Application Level Commands
+     */
+
+    fn get_file_ids(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 0)?;
+        let application = self.application(picc)?;
+        self.require_master_unless(application.key_settings, 0x02)?;
+
+        ok(application.files.keys().copied().collect())
+    }
+
+    fn get_file_settings(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 1)?;
+        let application = self.application(picc)?;
+        self.require_master_unless(application.key_settings, 0x02)?;
+        let file = application
+            .files
+            .get(&data[0])
+            .ok_or(Status::FileNotFound)?;
+
+        let mut bytes: Vec<u8> = Vec::new();
+        let file_type = match file.content {
+            FileContent::Data { backup: false, .. } => FILE_TYPE_STD_DATA,
+            FileContent::Data { backup: true, .. } => FILE_TYPE_BACKUP_DATA,
+            FileContent::Value { .. } => FILE_TYPE_VALUE,
+            FileContent::Record { cyclic: false, .. } => FILE_TYPE_LINEAR_RECORD,
+            FileContent::Record { cyclic: true, .. } => FILE_TYPE_CYCLIC_RECORD,
+        };
+        bytes.push(file_type);
+        bytes.push(file.communication);
+        bytes.extend(file.access_rights);
+
+        let io = |result: std::io::Result<()>| result.map_err(length_error);
+        match file.content {
+            FileContent::Data { ref data, .. } => {
+                io(bytes.write_u24::<LittleEndian>(data.len() as u32))?;
+            }
+            FileContent::Value {
+                lower_limit,
+                upper_limit,
+                limited_credit_value,
+                limited_credit_enabled,
+                ..
+            } => {
+                io(bytes.write_i32::<LittleEndian>(lower_limit))?;
+                io(bytes.write_i32::<LittleEndian>(upper_limit))?;
+                io(bytes.write_i32::<LittleEndian>(limited_credit_value))?;
+                bytes.push(limited_credit_enabled as u8);
+            }
+            FileContent::Record {
+                record_size,
+                max_no_records,
+                ref records,
+                ..
+            } => {
+                io(bytes.write_u24::<LittleEndian>(record_size as u32))?;
+                io(bytes.write_u24::<LittleEndian>(max_no_records as u32))?;
+                io(bytes.write_u24::<LittleEndian>(records.len() as u32))?;
+            }
+        }
+
+        ok(bytes)
+    }
+
+    fn change_file_settings(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        let (&file_no, data) = data.split_first().ok_or(Status::LengthError)?;
+        let auth = self.auth.clone();
+        let file = self.file(picc, file_no)?;
+
+        let settings = match (file.change_key(), auth) {
+            (ACCESS_FREE, _) => data.to_vec(),
+            (ACCESS_DENIED, _) => return Err(Status::PermissionDenied),
+            (_, None) => return Err(Status::AuthenticationError),
+            (change_key, Some((key_no, session_key))) => {
+                if key_no != change_key {
+                    return Err(Status::PermissionDenied);
+                }
+                expect_len(data, 8)?;
+                let settings = decipher(&session_key, data)?;
+                if settings[3..5] != mifare_utils::crc_checksum(&settings[0..3]) {
+                    return Err(Status::IntegrityError);
+                }
+                settings[0..3].to_vec()
+            }
+        };
+        expect_len(&settings, 3)?;
+
+        file.communication = settings[0];
+        file.access_rights = [settings[1], settings[2]];
+
+        ok(Vec::new())
+    }
+
+    fn create_file(&mut self, picc: &mut Picc, command: u8, data: &[u8]) -> CommandResult {
+        if self.aid == PICC_APPLICATION {
+            return Err(Status::PermissionDenied);
+        }
+        let key_settings = self.application(picc)?.key_settings;
+        self.require_master_unless(key_settings, 0x04)?;
+
+        let mut cursor = Cursor::new(data);
+        let file_no = cursor.read_u8().map_err(length_error)?;
+        let communication = cursor.read_u8().map_err(length_error)?;
+        let mut access_rights = [0u8; 2];
+        std::io::Read::read_exact(&mut cursor, &mut access_rights).map_err(length_error)?;
+
+        let content = match command {
+            0xCD | 0xCB => {
+                let file_size = cursor.read_u24::<LittleEndian>().map_err(length_error)?;
+                FileContent::Data {
+                    backup: command == 0xCB,
+                    data: vec![0; file_size as usize],
+                }
+            }
+            0xCC => {
+                let lower_limit = cursor.read_i32::<LittleEndian>().map_err(length_error)?;
+                let upper_limit = cursor.read_i32::<LittleEndian>().map_err(length_error)?;
+                let value = cursor.read_i32::<LittleEndian>().map_err(length_error)?;
+                let limited_credit_enabled = cursor.read_u8().map_err(length_error)? & 0x01 != 0;
+                if lower_limit > upper_limit || value < lower_limit || value > upper_limit {
+                    return Err(Status::BoundaryError);
+                }
+                FileContent::Value {
+                    value,
+                    lower_limit,
+                    upper_limit,
+                    limited_credit_value: 0,
+                    limited_credit_enabled,
+                }
+            }
+            _ => {
+                let record_size = cursor.read_u24::<LittleEndian>().map_err(length_error)?;
+                let max_no_records = cursor.read_u24::<LittleEndian>().map_err(length_error)?;
+                if record_size == 0 || max_no_records == 0 {
+                    return Err(Status::ParameterError);
+                }
+                FileContent::Record {
+                    cyclic: command == 0xC0,
+                    record_size: record_size as usize,
+                    max_no_records: max_no_records as usize,
+                    records: Vec::new(),
+                }
+            }
+        };
+        expect_len(data, cursor.position() as usize)?;
+
+        if file_no > MAX_FILE_NUMBER {
+            return Err(Status::ParameterError);
+        }
+        let application = self.application(picc)?;
+        if application.files.contains_key(&file_no) {
+            return Err(Status::DuplicateError);
+        }
+        application.files.insert(
+            file_no,
+            File {
+                communication,
+                access_rights,
+                content,
+            },
+        );
+
+        ok(Vec::new())
+    }
+
+    fn delete_file(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 1)?;
+        let application = self.application(picc)?;
+        self.require_master_unless(application.key_settings, 0x04)?;
+        application
+            .files
+            .remove(&data[0])
+            .ok_or(Status::FileNotFound)?;
+        self.transaction.files.remove(&data[0]);
+
+        ok(Vec::new())
+    }
+
+    /*
+     * Command Set - Data Manipulation Commands
+     */
+
+    /// Parses the file number, offset and length every data and record command starts with.
+    fn parse_access(data: &[u8]) -> Result<(u8, usize, usize), Status> {
+        let mut cursor = Cursor::new(data);
+        let file_no = cursor.read_u8().map_err(length_error)?;
+        let offset = cursor.read_u24::<LittleEndian>().map_err(length_error)?;
+        let length = cursor.read_u24::<LittleEndian>().map_err(length_error)?;
+        Ok((file_no, offset as usize, length as usize))
+    }
+
+    fn read_data(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 7)?;
+        let (file_no, offset, length) = Self::parse_access(data)?;
+        let file = self.file(picc, file_no)?;
+        let communication = self.access(file, &[file.read_key(), file.read_write_key()])?;
+
+        let content = match file.content {
+            FileContent::Data { ref data, .. } => data,
+            _ => return Err(Status::ParameterError),
+        };
+        // A length of zero reads the whole file from the offset on.
+        let end = if length == 0 {
+            content.len()
+        } else {
+            offset + length
+        };
+        if offset > content.len() || end > content.len() {
+            return Err(Status::BoundaryError);
+        }
+
+        let response = communication.encode(&content[offset..end])?;
+        self.respond(response, MAX_FRAME_SIZE)
+    }
+
+    /// Starts a write data or write record command, the data may follow in additional frames.
+    fn write(&mut self, picc: &mut Picc, command: u8, data: &[u8]) -> CommandResult {
+        if data.len() < 7 {
+            return Err(Status::LengthError);
+        }
+        let (file_no, offset, length) = Self::parse_access(data)?;
+        let file = self.file(picc, file_no)?;
+        let communication = self.access(file, &[file.write_key(), file.read_write_key()])?;
+
+        match (command, &file.content) {
+            (0x3D, FileContent::Data { data, .. }) if offset + length > data.len() => {
+                return Err(Status::BoundaryError)
+            }
+            (0x3B, FileContent::Record { record_size, .. }) if offset + length > *record_size => {
+                return Err(Status::BoundaryError)
+            }
+            (0x3D, FileContent::Data { .. }) | (0x3B, FileContent::Record { .. }) => {}
+            _ => return Err(Status::ParameterError),
+        }
+        if length == 0 {
+            return Err(Status::LengthError);
+        }
+
+        self.pending = Pending::Write {
+            file_no,
+            offset,
+            length,
+            communication,
+            data: Vec::new(),
+        };
+        self.continue_frame(picc, &data[7..])
+    }
+
+    fn apply_write(
+        &mut self,
+        picc: &mut Picc,
+        file_no: u8,
+        offset: usize,
+        data: &[u8],
+    ) -> CommandResult {
+        let file = self.file(picc, file_no)?;
+        let end = offset + data.len();
+
+        if let FileContent::Data {
+            backup: false,
+            data: ref mut content,
+        } = file.content
+        {
+            content[offset..end].copy_from_slice(data);
+            return ok(Vec::new());
+        }
+
+        let is_new_record = !self.transaction.files.contains_key(&file_no);
+        match Self::staged(&mut self.transaction, file, file_no) {
+            FileContent::Data { data: content, .. } => {
+                content[offset..end].copy_from_slice(data);
+            }
+            FileContent::Record {
+                cyclic,
+                record_size,
+                max_no_records,
+                records,
+            } => {
+                // The first write of a transaction starts a new record.
+                if is_new_record {
+                    if records.len() == *max_no_records {
+                        if !*cyclic {
+                            self.transaction.files.remove(&file_no);
+                            return Err(Status::BoundaryError);
+                        }
+                        records.remove(0);
+                    }
+                    records.push(vec![0; *record_size]);
+                }
+                let record = records.last_mut().expect("A record was just added");
+                record[offset..end].copy_from_slice(data);
+            }
+            FileContent::Value { .. } => return Err(Status::ParameterError),
+        }
+
+        ok(Vec::new())
+    }
+
+    fn get_value(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 1)?;
+        let file = self.file(picc, data[0])?;
+        let communication = self.access(
+            file,
+            &[file.read_key(), file.write_key(), file.read_write_key()],
+        )?;
+
+        let value = match file.content {
+            FileContent::Value { value, .. } => value,
+            _ => return Err(Status::ParameterError),
+        };
+
+        ok(communication.encode(&value.to_le_bytes())?)
+    }
+
+    /// Credit, debit and limited credit of a value file, effective after the commit.
+    fn change_value(&mut self, picc: &mut Picc, command: u8, data: &[u8]) -> CommandResult {
+        let (&file_no, data) = data.split_first().ok_or(Status::LengthError)?;
+        let file = self.file(picc, file_no)?;
+        let keys = match command {
+            0x0C => vec![file.read_write_key()],
+            0xDC => vec![file.read_key(), file.write_key(), file.read_write_key()],
+            _ => vec![file.write_key(), file.read_write_key()],
+        };
+        let communication = self.access(file, &keys)?;
+
+        let amount = communication.decode(data, 4)?;
+        let amount = i32::from_le_bytes(amount.try_into().expect("Length was checked"));
+        if amount < 0 {
+            return Err(Status::ParameterError);
+        }
+
+        let debits = self.transaction.debits.get(&file_no).copied().unwrap_or(0);
+        let debited = match Self::staged(&mut self.transaction, file, file_no) {
+            FileContent::Value {
+                value,
+                lower_limit,
+                upper_limit,
+                limited_credit_value,
+                limited_credit_enabled,
+            } => {
+                let new_value = match command {
+                    0xDC => value.checked_sub(amount),
+                    _ => value.checked_add(amount),
+                };
+                let new_value = new_value.ok_or(Status::BoundaryError)?;
+                if new_value < *lower_limit || new_value > *upper_limit {
+                    return Err(Status::BoundaryError);
+                }
+
+                if command == 0x1C {
+                    if !*limited_credit_enabled || debits > 0 || amount > *limited_credit_value {
+                        return Err(Status::BoundaryError);
+                    }
+                    *limited_credit_value = 0;
+                }
+                *value = new_value;
+
+                command == 0xDC
+            }
+            _ => return Err(Status::ParameterError),
+        };
+        if debited {
+            self.transaction.debits.insert(file_no, debits + amount);
+        }
+
+        ok(Vec::new())
+    }
+
+    fn read_records(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 7)?;
+        let (file_no, offset, length) = Self::parse_access(data)?;
+        let file = self.file(picc, file_no)?;
+        let communication = self.access(file, &[file.read_key(), file.read_write_key()])?;
+
+        let records = match file.content {
+            FileContent::Record { ref records, .. } => records,
+            _ => return Err(Status::ParameterError),
+        };
+        // The offset counts back from the newest record, a length of zero reads
+        // everything up to the oldest one. Records are sent oldest first.
+        if offset >= records.len() || length > records.len() - offset {
+            return Err(Status::BoundaryError);
+        }
+        let end = records.len() - offset;
+        let start = if length == 0 { 0 } else { end - length };
+
+        let response = communication.encode(&records[start..end].concat())?;
+        self.respond(response, MAX_FRAME_SIZE)
+    }
+
+    fn clear_record_file(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 1)?;
+        let file_no = data[0];
+        let file = self.file(picc, file_no)?;
+        self.access(file, &[file.read_write_key()])?;
+
+        match Self::staged(&mut self.transaction, file, file_no) {
+            FileContent::Record { records, .. } => records.clear(),
+            _ => return Err(Status::ParameterError),
+        }
+
+        ok(Vec::new())
+    }
+
+    fn commit_transaction(&mut self, picc: &mut Picc, data: &[u8]) -> CommandResult {
+        expect_len(data, 0)?;
+        let transaction = std::mem::take(&mut self.transaction);
+        let application = self.application(picc)?;
+
+        for (file_no, mut content) in transaction.files {
+            if let FileContent::Value {
+                ref mut limited_credit_value,
+                limited_credit_enabled: true,
+                ..
+            } = content
+            {
+                if let Some(debits) = transaction.debits.get(&file_no) {
+                    *limited_credit_value = *debits;
+                }
+            }
+            if let Some(file) = application.files.get_mut(&file_no) {
+                file.content = content;
+            }
+        }
+
+        ok(Vec::new())
+    }
+
+    fn abort_transaction(&mut self, data: &[u8]) -> CommandResult {
+        expect_len(data, 0)?;
+        self.abort();
+
+        ok(Vec::new())
+    }
+}
+
+#[test]
+pub fn mifare_desfire_simulation_card_test() {
+    use super::mifare_desfire::*;
+    use super::NfcCard;
+
+    let free = FileSettingsAccessRights {
+        read: FileSettingsAccessRightsKey::Free,
+        write: FileSettingsAccessRightsKey::Free,
+        read_write: FileSettingsAccessRightsKey::Free,
+        change_access: FileSettingsAccessRightsKey::Free,
+    };
+    let secured = || FileSettingsAccessRights {
+        read: FileSettingsAccessRightsKey::Key01,
+        write: FileSettingsAccessRightsKey::Key01,
+        read_write: FileSettingsAccessRightsKey::Key01,
+        change_access: FileSettingsAccessRightsKey::MasterKey,
+    };
+    let key = hex!("01 23 45 67 89 AB CD EF FE DC BA 98 76 54 32 10");
+    let aid = hex!("12 34 56");
+
+    let emulator = MiFareDESFireSimulationCard::new();
+    let mut nfc_card = NfcCard::new(emulator.clone());
+    assert_eq!(nfc_card.get_atr().unwrap(), ATR);
+    let card = MiFareDESFireCard::new(nfc_card);
+    assert!(MiFareDESFireCard::is_compatible(&card.card));
+    assert_eq!(card.get_version().unwrap().uid, UID);
+    assert!(card.authenticate(0, &key).is_err());
+    card.authenticate(0, &DEFAULT_KEY).unwrap();
+
+    let settings = KeySettings {
+        access_rights: KeySettingsAccessRights::MasterKey,
+        master_key_settings_changeable: true,
+        master_key_not_required_create_delete: false,
+        master_key_not_required_directory_access: false,
+        master_key_changeable: true,
+    };
+    card.create_application(aid, settings, 2).unwrap();
+    assert_eq!(card.get_application_ids().unwrap(), vec![aid]);
+    card.select_application(aid).unwrap();
+    assert!(card.get_file_ids().is_err());
+
+    // Key 1 guards the secured files, it is changed with the master key.
+    let session_key = card.authenticate(0, &DEFAULT_KEY).unwrap();
+    card.change_key(1, false, &DEFAULT_KEY, &key, &session_key)
+        .unwrap();
+    assert_eq!(card.get_key_version(1).unwrap(), key_version(&key));
+
+    card.create_value_file(
+        1,
+        FileSettingsCommunication::PlainText,
+        free,
+        0,
+        1000,
+        0,
+        true,
+    )
+    .unwrap();
+    card.create_backup_data_file(2, FileSettingsCommunication::Enciphered, secured(), 100)
+        .unwrap();
+    card.create_cyclic_record_file(3, FileSettingsCommunication::MACed, secured(), 4, 3)
+        .unwrap();
+    assert_eq!(card.get_file_ids().unwrap(), vec![1, 2, 3]);
+
+    // Value changes only count once they are committed.
+    card.credit(1, 500, Encryption::PlainText).unwrap();
+    assert_eq!(card.get_value(1, Encryption::PlainText).unwrap(), 0);
+    card.commit_transaction().unwrap();
+    card.debit(1, 120, Encryption::PlainText).unwrap();
+    card.debit(1, 80, Encryption::PlainText).unwrap();
+    assert!(card.debit(1, 400, Encryption::PlainText).is_err());
+    card.commit_transaction().unwrap();
+    assert_eq!(card.get_value(1, Encryption::PlainText).unwrap(), 300);
+    assert!(matches!(
+        card.get_file_settings(1).unwrap(),
+        FileSettings::ValueFile {
+            limited_credit_value: 200,
+            ..
+        }
+    ));
+    card.credit(1, 700, Encryption::PlainText).unwrap();
+    card.abort_transaction().unwrap();
+    assert!(card.limited_credit(1, 250, Encryption::PlainText).is_err());
+    card.limited_credit(1, 200, Encryption::PlainText).unwrap();
+    card.commit_transaction().unwrap();
+    assert_eq!(card.get_value(1, Encryption::PlainText).unwrap(), 500);
+
+    // Secured files need key 1 and its session key, 100 bytes span several frames.
+    let data: Vec<u8> = (0..100).collect();
+    assert!(card.read_data(2, 0, 0, Encryption::PlainText).is_err());
+    let session_key = card.authenticate(1, &key).unwrap();
+    card.write_data(2, 0, &data, Encryption::Encrypted(session_key.clone()))
+        .unwrap();
+    card.commit_transaction().unwrap();
+    assert_eq!(
+        card.read_data(2, 0, 0, Encryption::Encrypted(session_key.clone()))
+            .unwrap(),
+        data
+    );
+
+    for record in [b"abcd", b"efgh", b"ijkl", b"mnop"] {
+        card.write_record(3, 0, record, Encryption::MACed(session_key.clone()))
+            .unwrap();
+        card.commit_transaction().unwrap();
+    }
+    assert_eq!(
+        card.read_record(3, 0, 2, Encryption::MACed(session_key.clone()))
+            .unwrap(),
+        b"ijklmnop"
+    );
+    assert!(card
+        .read_record(3, 0, 0, Encryption::MACed(DEFAULT_KEY.to_vec()))
+        .is_err());
+
+    // The card keeps its memory when it is put on the reader again.
+    let card = MiFareDESFireCard::new(NfcCard::new(emulator));
+    card.select_application(aid).unwrap();
+    assert_eq!(card.get_value(1, Encryption::PlainText).unwrap(), 500);
+    card.authenticate(1, &key).unwrap();
+    card.select_application(PICC_APPLICATION).unwrap();
+    card.authenticate(0, &DEFAULT_KEY).unwrap();
+    card.format_picc().unwrap();
+    assert!(card.get_application_ids().unwrap().is_empty());
+}
@@ -1,11 +1,12 @@
-use block_modes::block_padding::ZeroPadding;
+use aes::Aes256;
+use block_modes::block_padding::{NoPadding, ZeroPadding};
 use block_modes::cipher::{BlockCipher, BlockDecrypt, BlockEncrypt, NewBlockCipher};
 use block_modes::{BlockMode, Cbc};
 use des::TdesEde2;
 use generic_array::GenericArray;
 use rand::RngCore;
 
-use super::NfcResult;
+use super::{NfcErrorKind, NfcResult};
 
 /// Communication to the mifare desfire always requires the tdes decribt
 struct MiFareTdes {
@@ -39,14 +40,54 @@ impl BlockDecrypt for MiFareTdes {
     }
 }
 
-pub fn tdes_encrypt(key: &[u8], value: &[u8]) -> NfcResult<Vec<u8>> {
+/// Communication to the generic nfc cards and the ascii-pay app uses AES-256
+struct NfcAes {
+    cipher: Aes256,
+}
+
+impl NewBlockCipher for NfcAes {
+    type KeySize = <Aes256 as NewBlockCipher>::KeySize;
+
+    fn new(key: &GenericArray<u8, Self::KeySize>) -> Self {
+        NfcAes {
+            cipher: Aes256::new(key),
+        }
+    }
+}
+
+impl BlockCipher for NfcAes {
+    type BlockSize = <Aes256 as BlockCipher>::BlockSize;
+    type ParBlocks = <Aes256 as BlockCipher>::ParBlocks;
+}
+
+impl BlockEncrypt for NfcAes {
+    fn encrypt_block(&self, block: &mut GenericArray<u8, Self::BlockSize>) {
+        self.cipher.encrypt_block(block)
+    }
+}
+
+impl BlockDecrypt for NfcAes {
+    fn decrypt_block(&self, block: &mut GenericArray<u8, Self::BlockSize>) {
+        self.cipher.decrypt_block(block)
+    }
+}
+
+/// Expands a single DES key to a 2TDEA key, other lengths are rejected.
+pub fn tdes_key(key: &[u8]) -> NfcResult<Vec<u8>> {
     let mut v = Vec::with_capacity(16);
     v.extend(key);
 
-    if key.len() == 8 {
-        v.extend(key);
+    match key.len() {
+        8 => v.extend(key),
+        16 => {}
+        _ => return Err(NfcErrorKind::UnknownError.into()),
     }
 
+    Ok(v)
+}
+
+pub fn tdes_encrypt(key: &[u8], value: &[u8]) -> NfcResult<Vec<u8>> {
+    let v = tdes_key(key)?;
     let key = GenericArray::from_slice(&v);
 
     let iv = GenericArray::from_slice(&hex!("00 00 00 00 00 00 00 00"));
@@ -56,29 +97,51 @@ pub fn tdes_encrypt(key: &[u8], value: &[u8]) -> NfcResult<Vec<u8>> {
 }
 
 pub fn tdes_decrypt(key: &[u8], value: &[u8]) -> NfcResult<Vec<u8>> {
-    let mut v = Vec::with_capacity(16);
-    v.extend(key);
-
-    if key.len() == 8 {
-        v.extend(key);
+    // Every message of the card holds at least one block
+    if value.is_empty() {
+        return Err(NfcErrorKind::IntegrityError.into());
     }
 
+    let v = tdes_key(key)?;
     let key = GenericArray::from_slice(&v);
 
+    // Trailing zeros may be data, e.g. of a random number, so the padding is left to the caller
     let iv = GenericArray::from_slice(&hex!("00 00 00 00 00 00 00 00"));
-    let cipher: Cbc<MiFareTdes, ZeroPadding> = Cbc::new(MiFareTdes::new(key), iv);
+    let cipher: Cbc<MiFareTdes, NoPadding> = Cbc::new(MiFareTdes::new(key), iv);
 
     Ok(cipher.decrypt_vec(value)?)
 }
 
-pub fn mac(key: &[u8], value: &[u8]) -> NfcResult<[u8; 4]> {
-    let mut v = Vec::with_capacity(16);
-    v.extend(key);
+pub fn aes_encrypt(key: &[u8], value: &[u8]) -> NfcResult<Vec<u8>> {
+    if key.len() != 32 {
+        return Err(NfcErrorKind::UnknownError.into());
+    }
+    let key = GenericArray::from_slice(key);
 
-    if key.len() == 8 {
-        v.extend(key);
+    let iv = GenericArray::from_slice(&[0u8; 16]);
+    let cipher: Cbc<NfcAes, ZeroPadding> = Cbc::new(NfcAes::new(key), iv);
+
+    Ok(cipher.encrypt_vec(value))
+}
+
+pub fn aes_decrypt(key: &[u8], value: &[u8]) -> NfcResult<Vec<u8>> {
+    if value.is_empty() {
+        return Err(NfcErrorKind::IntegrityError.into());
     }
+    if key.len() != 32 {
+        return Err(NfcErrorKind::UnknownError.into());
+    }
+    let key = GenericArray::from_slice(key);
+
+    // Like `tdes_decrypt`, random numbers may end in zeros
+    let iv = GenericArray::from_slice(&[0u8; 16]);
+    let cipher: Cbc<NfcAes, NoPadding> = Cbc::new(NfcAes::new(key), iv);
 
+    Ok(cipher.decrypt_vec(value)?)
+}
+
+pub fn mac(key: &[u8], value: &[u8]) -> NfcResult<[u8; 4]> {
+    let v = tdes_key(key)?;
     let key = GenericArray::from_slice(&v);
 
     let iv = GenericArray::from_slice(&hex!("00 00 00 00 00 00 00 00"));
@@ -86,7 +149,10 @@ pub fn mac(key: &[u8], value: &[u8]) -> NfcResult<[u8; 4]> {
 
     let encrypted = cipher.encrypt_vec(value);
 
-    let index = encrypted.len() - 8;
+    let index = match encrypted.len().checked_sub(8) {
+        Some(index) => index,
+        None => return Err(NfcErrorKind::IntegrityError.into()),
+    };
 
     Ok([
         encrypted[index],
@@ -140,6 +206,50 @@ pub fn crc_test() {
     assert_eq!(crc, hex!("26 CF"));
 }
 
+#[test]
+pub fn tdes_decrypt_test() {
+    let key = hex!("00 11 22 33 44 55 66 77 88 99 AA BB CC DD EE FF");
+
+    // The card enciphers with a plain TDES in CBC mode
+    let card: Cbc<TdesEde2, NoPadding> = Cbc::new(
+        TdesEde2::new(GenericArray::from_slice(&key)),
+        &Default::default(),
+    );
+
+    // A random number of the card may end in zero, none of it must get lost
+    let rnd_b = hex!("01 23 45 67 89 AB CD 00");
+    let ek_rnd_b = card.clone().encrypt_vec(&rnd_b);
+    assert_eq!(tdes_decrypt(&key, &ek_rnd_b).unwrap(), rnd_b);
+
+    let zeros = [0u8; 16];
+    let encrypted = card.encrypt_vec(&zeros);
+    assert_eq!(tdes_decrypt(&key, &encrypted).unwrap(), zeros);
+
+    assert!(tdes_decrypt(&key, &[]).is_err());
+}
+
+#[test]
+pub fn aes_test() {
+    // AES-256 known answer of FIPS-197 for the zero key and block
+    let key = [0u8; 32];
+    let encrypted = aes_encrypt(&key, &[0u8; 16]).unwrap();
+    assert_eq!(
+        encrypted,
+        hex!("DC 95 C0 78 A2 40 89 89 AD 48 A2 14 92 84 20 87")
+    );
+    assert_eq!(aes_decrypt(&key, &encrypted).unwrap(), [0u8; 16]);
+
+    // The second block is chained with the first one
+    let encrypted = aes_encrypt(&key, &[0u8; 32]).unwrap();
+    assert_eq!(
+        encrypted[16..],
+        aes_encrypt(&key, &encrypted[..16]).unwrap()[..]
+    );
+
+    assert!(aes_decrypt(&key, &[]).is_err());
+    assert!(aes_encrypt(&key[..16], &[0u8; 16]).is_err());
+}
+
 pub fn generate_key<const N: usize>() -> [u8; N] {
     let mut data = [0u8; N];
     rand::thread_rng().fill_bytes(&mut data);
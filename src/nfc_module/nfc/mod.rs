@@ -1,12 +1,21 @@
+pub mod auth_state;
+pub mod card_transport;
 mod iso_14443_card;
+pub mod iso_14443_simulation_card;
 pub mod mifare_desfire;
 mod mifare_desfire_card;
+pub mod mifare_desfire_simulation_card;
 pub mod mifare_utils;
 pub mod nfc_card;
 pub mod simulation_card;
 pub mod utils;
+pub mod vpcd_card;
 
+pub use card_transport::CardTransport;
 pub use iso_14443_card::Iso14443Card;
+pub use iso_14443_simulation_card::Iso14443SimulationCard;
 pub use mifare_desfire::MiFareDESFireCard;
+pub use mifare_desfire_simulation_card::MiFareDESFireSimulationCard;
 pub use nfc_card::NfcCard;
-pub use utils::{NfcError, NfcResult};
+pub use utils::{CardStatus, NfcError, NfcErrorKind, NfcResult};
+pub use vpcd_card::VpcdCard;
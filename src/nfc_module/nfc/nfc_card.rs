@@ -1,14 +1,16 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use base64::engine::general_purpose;
+use base64::Engine;
 use pcsc;
 
-use crate::websocket_server::CardTypeDto;
+use crate::websocket_server::{AuthStageDto, CardStatusDto, CardTypeDto};
 
-use super::{simulation_card::SimulationCard, utils::*};
+use super::{auth_state::AuthState, card_transport::CardTransport, utils::*};
 
 enum NfcCardImpl {
-    Pcsc(pcsc::Card),
-    Simulation(SimulationCard),
+    Connected(Box<dyn CardTransport>),
+    /// Card left the reader, its session is kept until the given unix time.
     Timeout(u64),
 }
 
@@ -18,54 +20,50 @@ pub struct NfcCard {
     auth_data: Vec<u8>,
     atr: Option<Vec<u8>>,
     card_type: Option<CardTypeDto>,
+    handler: Option<&'static str>,
+    auth: AuthState,
 }
 
 impl NfcCard {
-    pub fn new(card: pcsc::Card) -> Self {
+    pub fn new<T: CardTransport + 'static>(transport: T) -> Self {
         NfcCard {
-            card: NfcCardImpl::Pcsc(card),
+            card: NfcCardImpl::Connected(Box::new(transport)),
             id: None,
             auth_data: Vec::new(),
             atr: None,
             card_type: None,
+            handler: None,
+            auth: AuthState::new(),
         }
     }
-    pub fn simulate(card: SimulationCard) -> Self {
-        NfcCard {
-            card: NfcCardImpl::Simulation(card),
-            id: None,
-            auth_data: Vec::new(),
-            atr: None,
-            card_type: None,
+
+    /// Releases the card without resetting it, a dropped card is reset instead.
+    pub fn disconnect(self) -> NfcResult<()> {
+        match self.card {
+            NfcCardImpl::Connected(transport) => transport.disconnect(),
+            NfcCardImpl::Timeout(_) => Ok(()),
         }
     }
 
-    pub fn get_attribute(&self, attribute: pcsc::Attribute) -> NfcResult<Vec<u8>> {
+    /// Connects to the card again, e.g. after it was reset by another application.
+    pub fn reconnect(&mut self) -> NfcResult<()> {
         match self.card {
-            NfcCardImpl::Pcsc(ref card) => {
-                let data_len = card.get_attribute_len(attribute)?;
-
-                let mut data_buf = vec![0; data_len];
-                let data = card.get_attribute(attribute, &mut data_buf)?;
+            NfcCardImpl::Connected(ref mut transport) => transport.reconnect(),
+            NfcCardImpl::Timeout(_) => Err(NfcErrorKind::CommunicationError.into()),
+        }
+    }
 
-                Ok(data.to_vec())
-            }
-            NfcCardImpl::Simulation(ref card) => card.get_attribute(attribute),
-            NfcCardImpl::Timeout(_) => NfcResult::Err(NfcError::CommunicationError),
+    pub fn get_attribute(&self, attribute: pcsc::Attribute) -> NfcResult<Vec<u8>> {
+        match self.card {
+            NfcCardImpl::Connected(ref transport) => transport.get_attribute(attribute),
+            NfcCardImpl::Timeout(_) => Err(NfcErrorKind::CommunicationError.into()),
         }
     }
 
     pub fn transmit(&self, query: &[u8]) -> NfcResult<Vec<u8>> {
         match self.card {
-            NfcCardImpl::Pcsc(ref card) => {
-                println!("transmit {:X?}", query);
-                let mut data_buf = [0; pcsc::MAX_BUFFER_SIZE];
-                let data = card.transmit(query, &mut data_buf)?;
-
-                Ok(data.to_vec())
-            }
-            NfcCardImpl::Simulation(ref card) => card.transmit(query),
-            NfcCardImpl::Timeout(_) => NfcResult::Err(NfcError::CommunicationError),
+            NfcCardImpl::Connected(ref transport) => transport.transmit(query),
+            NfcCardImpl::Timeout(_) => Err(NfcErrorKind::CommunicationError.into()),
         }
     }
 
@@ -73,7 +71,10 @@ impl NfcCard {
         if let Some(ref atr) = self.atr {
             return Ok(atr.clone());
         }
-        let atr = self.get_attribute(pcsc::Attribute::AtrString)?;
+        let atr = match self.card {
+            NfcCardImpl::Connected(ref transport) => transport.atr()?,
+            NfcCardImpl::Timeout(_) => return Err(NfcErrorKind::CommunicationError.into()),
+        };
         self.atr = Some(atr.clone());
         Ok(atr)
     }
@@ -93,6 +94,39 @@ impl NfcCard {
         self.card_type
     }
 
+    pub fn set_handler(&mut self, handler: &'static str) {
+        self.handler = Some(handler);
+    }
+
+    pub fn set_auth_stage(&mut self, auth_stage: AuthStageDto) {
+        self.auth.advance(auth_stage);
+    }
+
+    pub fn auth_state(&self) -> &AuthState {
+        &self.auth
+    }
+
+    pub fn auth_state_mut(&mut self) -> &mut AuthState {
+        &mut self.auth
+    }
+
+    pub fn status(&self) -> CardStatusDto {
+        CardStatusDto {
+            card_id: self
+                .id
+                .as_ref()
+                .map(|id| general_purpose::STANDARD.encode(id)),
+            handler: self.handler.map(|handler| handler.to_owned()),
+            card_type: self.card_type,
+            // A removed card keeps its progress, so the flow can still be finished.
+            stage: if self.is_in_timeout_mode() {
+                AuthStageDto::Removed
+            } else {
+                self.auth.stage()
+            },
+        }
+    }
+
     pub fn set_auth_data(&mut self, data: Vec<u8>) {
         self.auth_data = data;
     }
@@ -105,13 +139,23 @@ impl NfcCard {
         self.id = Some(id);
     }
 
+    /// Id of the card once a handler has read it.
+    pub fn id(&self) -> Option<&[u8]> {
+        self.id.as_deref()
+    }
+
     pub fn get_id(&self) -> Option<Vec<u8>> {
-        if let NfcCardImpl::Simulation(_) = self.card {
-            std::thread::sleep(std::time::Duration::from_secs(1));
-        }
         self.id.clone()
     }
 
+    /// Whether a connected card was taken away without the reader telling.
+    pub fn is_gone(&self) -> bool {
+        match self.card {
+            NfcCardImpl::Connected(ref transport) => !transport.is_present(),
+            NfcCardImpl::Timeout(_) => false,
+        }
+    }
+
     pub fn is_in_timeout_mode(&self) -> bool {
         matches!(self.card, NfcCardImpl::Timeout(_))
     }
@@ -131,8 +175,7 @@ impl NfcCard {
 
     pub fn has_timeout_occurred(&self) -> bool {
         match self.card {
-            NfcCardImpl::Pcsc(_) => false,
-            NfcCardImpl::Simulation(_) => false,
+            NfcCardImpl::Connected(_) => false,
             NfcCardImpl::Timeout(timeout) => {
                 let time = SystemTime::now()
                     .duration_since(UNIX_EPOCH)
@@ -1,6 +1,6 @@
 use crate::nfc_module::nfc::utils::bytes_to_string;
 
-use super::NfcResult;
+use super::{CardTransport, NfcResult};
 
 pub struct SimulationCard {}
 
@@ -9,8 +9,10 @@ impl SimulationCard {
     pub fn new() -> Self {
         Self {}
     }
+}
 
-    pub fn get_attribute(&self, attribute: pcsc::Attribute) -> NfcResult<Vec<u8>> {
+impl CardTransport for SimulationCard {
+    fn get_attribute(&self, attribute: pcsc::Attribute) -> NfcResult<Vec<u8>> {
         println!("[SimulationCard::get_attribute] {attribute:?}");
 
         match attribute {
@@ -21,7 +23,7 @@ impl SimulationCard {
         }
     }
 
-    pub fn transmit(&self, query: &[u8]) -> NfcResult<Vec<u8>> {
+    fn transmit(&self, query: &[u8]) -> NfcResult<Vec<u8>> {
         println!("[SimulationCard::transmit] {}", bytes_to_string(query));
 
         if query == hex!("FF CA 00 00 00") {
@@ -30,4 +32,12 @@ impl SimulationCard {
 
         Ok(Vec::new())
     }
+
+    fn reconnect(&mut self) -> NfcResult<()> {
+        Ok(())
+    }
+
+    fn disconnect(self: Box<Self>) -> NfcResult<()> {
+        Ok(())
+    }
 }
@@ -2,8 +2,8 @@ use std::io::Cursor;
 
 use pcsc;
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum NfcError {
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NfcErrorKind {
     PermissionDenied,
     CommunicationError,
     ByteParseError,
@@ -11,23 +11,110 @@ pub enum NfcError {
     UnknownError,
 }
 
+/// Status a card answered a failed command with.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CardStatus {
+    /// Native status byte, e.g. of a DESFire card or the HCE app.
+    Byte(u8),
+    /// ISO 7816 status words SW1/SW2.
+    Words(u8, u8),
+}
+
+impl std::fmt::Display for CardStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CardStatus::Byte(status) => write!(f, "status {status:02X}"),
+            CardStatus::Words(sw1, sw2) => write!(f, "SW {sw1:02X} {sw2:02X}"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct NfcError {
+    pub kind: NfcErrorKind,
+    /// Name of the failing card command, e.g. `get_file_settings`.
+    pub command: Option<&'static str>,
+    pub status: Option<CardStatus>,
+    pub pcsc_error: Option<pcsc::Error>,
+}
+
+impl NfcError {
+    pub fn new(kind: NfcErrorKind) -> Self {
+        Self {
+            kind,
+            command: None,
+            status: None,
+            pcsc_error: None,
+        }
+    }
+
+    pub fn with_command(mut self, command: &'static str) -> Self {
+        self.command = Some(command);
+        self
+    }
+
+    pub fn with_status(mut self, status: CardStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
+}
+
+impl std::fmt::Display for NfcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.kind)?;
+        if let Some(command) = self.command {
+            write!(f, " in {command}")?;
+        }
+        if let Some(status) = self.status {
+            write!(f, ", {status}")?;
+        }
+        if let Some(error) = self.pcsc_error {
+            write!(f, ", PC/SC: {error}")?;
+        }
+        Ok(())
+    }
+}
+
 pub type NfcResult<T> = Result<T, NfcError>;
 
+/// Adds the name of the failing command to an error that does not know it yet.
+pub trait NfcResultExt<T> {
+    fn command(self, command: &'static str) -> NfcResult<T>;
+}
+
+impl<T> NfcResultExt<T> for NfcResult<T> {
+    fn command(self, command: &'static str) -> NfcResult<T> {
+        self.map_err(|mut error| {
+            error.command.get_or_insert(command);
+            error
+        })
+    }
+}
+
+impl From<NfcErrorKind> for NfcError {
+    fn from(kind: NfcErrorKind) -> Self {
+        NfcError::new(kind)
+    }
+}
+
 impl From<pcsc::Error> for NfcError {
-    fn from(_err: pcsc::Error) -> Self {
-        NfcError::CommunicationError
+    fn from(err: pcsc::Error) -> Self {
+        NfcError {
+            pcsc_error: Some(err),
+            ..NfcError::new(NfcErrorKind::CommunicationError)
+        }
     }
 }
 
 impl From<block_modes::BlockModeError> for NfcError {
     fn from(_err: block_modes::BlockModeError) -> Self {
-        NfcError::UnknownError
+        NfcError::new(NfcErrorKind::UnknownError)
     }
 }
 
 impl From<std::io::Error> for NfcError {
     fn from(_err: std::io::Error) -> Self {
-        NfcError::ByteParseError
+        NfcError::new(NfcErrorKind::ByteParseError)
     }
 }
 
@@ -61,11 +148,11 @@ where
     Self: std::marker::Sized,
 {
     fn from_bytes(cursor: &mut Cursor<&[u8]>) -> NfcResult<Self> {
-        panic!("Unsupported operation!")
+        Err(NfcErrorKind::UnknownError.into())
     }
 
     fn to_bytes(&self, bytes: &mut Vec<u8>) -> NfcResult<()> {
-        panic!("Unsupported operation!")
+        Err(NfcErrorKind::UnknownError.into())
     }
 
     fn from_byte(byte: u8) -> NfcResult<Self> {
@@ -78,10 +165,9 @@ where
 
         self.to_bytes(&mut bytes)?;
 
-        if bytes.len() > 1 {
-            Err(NfcError::ByteParseError)
-        } else {
-            Ok(bytes[0])
+        match bytes.as_slice() {
+            [byte] => Ok(*byte),
+            _ => Err(NfcErrorKind::ByteParseError.into()),
         }
     }
 
@@ -0,0 +1,179 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv6Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use super::{CardTransport, NfcErrorKind, NfcResult};
+
+/// Port the vsmartcard `vpcd` driver listens on, `vicc` connects to it by default.
+pub const DEFAULT_VPCD_PORT: u16 = 35963;
+
+/// Completes a listen address that names only a host with the default vpcd port.
+pub fn vpcd_listen_address(address: &str) -> String {
+    if address.parse::<SocketAddr>().is_ok() {
+        return address.to_owned();
+    }
+    if let Some((_, port)) = address.rsplit_once(':') {
+        if port.parse::<u16>().is_ok() && address.parse::<Ipv6Addr>().is_err() {
+            return address.to_owned();
+        }
+    }
+
+    match address.parse::<Ipv6Addr>() {
+        Ok(ip) => format!("[{ip}]:{DEFAULT_VPCD_PORT}"),
+        Err(_) => format!("{address}:{DEFAULT_VPCD_PORT}"),
+    }
+}
+
+const CTRL_OFF: u8 = 0x00;
+const CTRL_ON: u8 = 0x01;
+const CTRL_RESET: u8 = 0x02;
+const CTRL_ATR: u8 = 0x04;
+
+/// Time a virtual card gets to answer a single request.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Card connected through the vsmartcard `vpcd` protocol, e.g. `vicc` or the
+/// Android virtual card app.
+///
+/// The terminal takes the reader side: every message is prefixed with its length
+/// as big endian `u16`, single byte messages control the power of the card and
+/// everything else is an APDU.
+pub struct VpcdCard {
+    stream: TcpStream,
+    atr: Vec<u8>,
+}
+
+impl VpcdCard {
+    /// Powers on the card behind a freshly accepted connection and reads its ATR.
+    pub fn connect(stream: TcpStream) -> NfcResult<Self> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+
+        let mut card = Self {
+            stream,
+            atr: Vec::new(),
+        };
+        card.send(&[CTRL_ON])?;
+        card.atr = card.request(&[CTRL_ATR])?;
+        Ok(card)
+    }
+
+    fn send(&self, message: &[u8]) -> NfcResult<()> {
+        let length = u16::try_from(message.len())
+            .map_err(|_| NfcErrorKind::CommunicationError)?
+            .to_be_bytes();
+
+        let mut stream = &self.stream;
+        stream.write_all(&length)?;
+        stream.write_all(message)?;
+        Ok(())
+    }
+
+    fn request(&self, message: &[u8]) -> NfcResult<Vec<u8>> {
+        self.send(message)?;
+
+        let mut stream = &self.stream;
+        let mut length = [0u8; 2];
+        stream.read_exact(&mut length)?;
+        let mut response = vec![0u8; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut response)?;
+        Ok(response)
+    }
+}
+
+impl CardTransport for VpcdCard {
+    fn transmit(&self, query: &[u8]) -> NfcResult<Vec<u8>> {
+        // Single bytes are control messages, they are no valid APDU anyway.
+        if query.len() < 2 {
+            return Err(NfcErrorKind::CommunicationError.into());
+        }
+        self.request(query)
+    }
+
+    fn get_attribute(&self, attribute: pcsc::Attribute) -> NfcResult<Vec<u8>> {
+        match attribute {
+            pcsc::Attribute::AtrString => Ok(self.atr.clone()),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn reconnect(&mut self) -> NfcResult<()> {
+        self.send(&[CTRL_RESET])?;
+        self.atr = self.request(&[CTRL_ATR])?;
+        Ok(())
+    }
+
+    fn disconnect(self: Box<Self>) -> NfcResult<()> {
+        self.send(&[CTRL_OFF])?;
+        self.stream.shutdown(std::net::Shutdown::Both)?;
+        Ok(())
+    }
+
+    /// A virtual card is removed by closing its connection.
+    fn is_present(&self) -> bool {
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let present = match self.stream.peek(&mut [0u8]) {
+            Ok(0) => false,
+            Ok(_) => true,
+            Err(e) => e.kind() == ErrorKind::WouldBlock,
+        };
+        present && self.stream.set_nonblocking(false).is_ok()
+    }
+}
+
+#[test]
+pub fn vpcd_card_test() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+
+    // Minimal `vicc` that answers every APDU with its reversed bytes.
+    let vicc = std::thread::spawn(move || {
+        let mut stream = TcpStream::connect(address).unwrap();
+        loop {
+            let mut length = [0u8; 2];
+            if stream.read_exact(&mut length).is_err() {
+                return;
+            }
+            let mut message = vec![0u8; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut message).unwrap();
+
+            let response = match message[..] {
+                [CTRL_ATR] => vec![0x3B, 0x80, 0x80, 0x01, 0x01],
+                [CTRL_OFF] => return,
+                [_] => continue,
+                _ => message.iter().rev().copied().collect(),
+            };
+            stream
+                .write_all(&(response.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&response).unwrap();
+        }
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let card = VpcdCard::connect(stream).unwrap();
+    assert_eq!(card.atr().unwrap(), vec![0x3B, 0x80, 0x80, 0x01, 0x01]);
+    assert_eq!(
+        card.transmit(&[0x90, 0x60, 0x00]).unwrap(),
+        vec![0x00, 0x60, 0x90]
+    );
+    assert!(card.is_present());
+
+    Box::new(card).disconnect().unwrap();
+    vicc.join().unwrap();
+}
+
+#[test]
+pub fn vpcd_listen_address_test() {
+    assert_eq!(vpcd_listen_address("127.0.0.1:4242"), "127.0.0.1:4242");
+    assert_eq!(vpcd_listen_address("localhost:4242"), "localhost:4242");
+    assert_eq!(vpcd_listen_address("[::1]:4242"), "[::1]:4242");
+    assert_eq!(vpcd_listen_address("0.0.0.0"), "0.0.0.0:35963");
+    assert_eq!(vpcd_listen_address("localhost"), "localhost:35963");
+    assert_eq!(vpcd_listen_address("::1"), "[::1]:35963");
+}
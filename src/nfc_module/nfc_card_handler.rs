@@ -14,6 +14,13 @@ pub enum NfcCardHandlerWrapper {
 }
 
 impl NfcCardHandlerWrapper {
+    /// Names of all card handlers, in the order they are probed.
+    pub const HANDLERS: [&'static str; 3] = [
+        Iso14443Handler::NAME,
+        MiFareDESFireHandler::NAME,
+        GenericNfcHandler::NAME,
+    ];
+
     pub fn new(mut card: NfcCard) -> Self {
         let card_type = card.get_card_type();
         let is_nfc_id = matches!(card_type, Some(CardTypeDto::GenericNfc));
@@ -35,13 +42,25 @@ impl NfcCardHandlerWrapper {
         Self::UnsupportedCard(UnsupportedCardHandler::new(card))
     }
 
-    pub fn finish(self) -> NfcCard {
+    pub fn name(&self) -> &'static str {
         match self {
+            Self::Iso14443(_) => Iso14443Handler::NAME,
+            Self::MiFareDESFire(_) => MiFareDESFireHandler::NAME,
+            Self::MiFareClassic(_) => GenericNfcHandler::NAME,
+            Self::UnsupportedCard(_) => UnsupportedCardHandler::NAME,
+        }
+    }
+
+    pub fn finish(self) -> NfcCard {
+        let name = self.name();
+        let mut card = match self {
             Self::Iso14443(handler) => handler.finish(),
             Self::MiFareDESFire(handler) => handler.finish(),
             Self::MiFareClassic(handler) => handler.finish(),
             Self::UnsupportedCard(handler) => handler.finish(),
-        }
+        };
+        card.set_handler(name);
+        card
     }
 
     pub async fn handle_card_authentication(
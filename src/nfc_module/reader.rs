@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use tokio::runtime::Handle;
+
+use crate::application::ApplicationResponseContext;
+use crate::{ErrorCode, ServiceResult};
+
+use super::nfc::NfcCard;
+use super::{
+    handle_card_authentication, handle_card_challenge_response, handle_card_identify_response,
+    handle_card_register, handle_card_response_response, NfcCommand,
+};
+
+/// How often an idle reader checks its card for expired sessions.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Message to the actor of a single reader.
+pub enum ReaderMessage {
+    /// A card was put on the reader.
+    Inserted(NfcCard),
+    /// The card left the reader.
+    Removed,
+    /// A command for the card, answered through the given context.
+    Command(ApplicationResponseContext, NfcCommand),
+}
+
+/// What the command router knows about a reader.
+pub struct ReaderEntry {
+    sender: mpsc::Sender<ReaderMessage>,
+    has_card: bool,
+    /// Id of the card, once a handler has read it.
+    card_id: Option<Vec<u8>>,
+}
+
+impl ReaderEntry {
+    pub fn sender(&self) -> mpsc::Sender<ReaderMessage> {
+        self.sender.clone()
+    }
+}
+
+/// Readers by name, shared by the reader watcher, the reader actors and the command router.
+///
+/// Only held for lookups, never across card I/O.
+pub type ReaderMap = Arc<Mutex<HashMap<String, ReaderEntry>>>;
+
+pub fn lock_readers(readers: &ReaderMap) -> MutexGuard<'_, HashMap<String, ReaderEntry>> {
+    // The map stays consistent even if a holder panicked.
+    readers.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Picks the reader a command is meant for.
+///
+/// Without a reader the card is looked up by its id, and commands without a
+/// card id are only accepted as long as a single reader holds a card.
+pub fn select_reader(
+    readers: &HashMap<String, ReaderEntry>,
+    reader: Option<&str>,
+    command: &NfcCommand,
+) -> Result<String, (ErrorCode, String)> {
+    let mut cards = readers.iter().filter(|(_, entry)| entry.has_card);
+
+    match (reader, command.card_id()) {
+        (Some(reader), _) => match readers.get(reader) {
+            Some(entry) if entry.has_card => Ok(reader.to_owned()),
+            _ => Err((
+                ErrorCode::NoCard,
+                format!("No nfc card on reader '{reader}'!"),
+            )),
+        },
+        (None, Some(card_id)) => {
+            let mut cards = cards.peekable();
+            if cards.peek().is_none() {
+                return Err((ErrorCode::NoCard, "No nfc card found!".to_owned()));
+            }
+            cards
+                .find(|(_, entry)| entry.card_id.as_deref() == Some(card_id))
+                .map(|(name, _)| name.clone())
+                .ok_or((
+                    ErrorCode::CardMismatch,
+                    "No nfc card with this card id found!".to_owned(),
+                ))
+        }
+        (None, None) => match (cards.next(), cards.next()) {
+            (Some((name, _)), None) => Ok(name.clone()),
+            (None, _) => Err((ErrorCode::NoCard, "No nfc card found!".to_owned())),
+            (Some(_), Some(_)) => Err((
+                ErrorCode::BadRequest,
+                "Several readers hold a card, select one with 'reader'!".to_owned(),
+            )),
+        },
+    }
+}
+
+/// Handle to the actor of a reader, `stop` it to release the card.
+pub struct Reader {
+    name: String,
+    sender: mpsc::Sender<ReaderMessage>,
+    readers: ReaderMap,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Reader {
+    /// Starts the actor on a dedicated thread and registers it with the router.
+    ///
+    /// Card handlers are async, they are driven by the `runtime` of the module.
+    pub fn spawn(
+        name: &str,
+        context: &ApplicationResponseContext,
+        readers: &ReaderMap,
+        runtime: Handle,
+    ) -> ServiceResult<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let actor = ReaderActor {
+            name: name.to_owned(),
+            context: context.for_reader(name),
+            readers: readers.clone(),
+            runtime,
+            card: None,
+        };
+
+        let thread = thread::Builder::new()
+            .name(format!("nfc-reader-{name}"))
+            .spawn(move || actor.run(receiver))?;
+
+        lock_readers(readers).insert(
+            name.to_owned(),
+            ReaderEntry {
+                sender: sender.clone(),
+                has_card: false,
+                card_id: None,
+            },
+        );
+
+        Ok(Self {
+            name: name.to_owned(),
+            sender,
+            readers: readers.clone(),
+            thread,
+        })
+    }
+
+    pub fn send(&self, message: ReaderMessage) {
+        if self.sender.send(message).is_err() {
+            error!("Actor of nfc reader {} is gone. Drop message!", self.name);
+        }
+    }
+
+    /// Unregisters the reader and waits until its card is released.
+    pub fn stop(self) {
+        lock_readers(&self.readers).remove(&self.name);
+        drop(self.sender);
+        if self.thread.join().is_err() {
+            error!("Actor of nfc reader {} panicked", self.name);
+        }
+    }
+}
+
+struct ReaderActor {
+    name: String,
+    context: ApplicationResponseContext,
+    readers: ReaderMap,
+    runtime: Handle,
+    card: Option<NfcCard>,
+}
+
+impl ReaderActor {
+    fn run(mut self, receiver: mpsc::Receiver<ReaderMessage>) {
+        loop {
+            match receiver.recv_timeout(EXPIRY_INTERVAL) {
+                Ok(ReaderMessage::Inserted(card)) => self.insert(card),
+                Ok(ReaderMessage::Removed) => self.remove(),
+                Ok(ReaderMessage::Command(context, command)) => {
+                    self.handle_command(&context, command)
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => self.expire(),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        if let Some(card) = self.card.take() {
+            info!("Release nfc card");
+            if let Err(e) = card.disconnect() {
+                warn!("Cannot disconnect nfc card of reader {}: {}", self.name, e);
+            }
+            self.runtime.block_on(self.context.send_nfc_card_removed());
+            self.publish();
+        }
+    }
+
+    fn insert(&mut self, card: NfcCard) {
+        // Only a removed card that is still kept for its authentication can be left.
+        if self.card.take().is_some() {
+            info!("Remove nfc card");
+            self.runtime.block_on(self.context.send_nfc_card_removed());
+        }
+
+        let card = self
+            .runtime
+            .block_on(handle_card_authentication(&self.context, card));
+        self.card = Some(card);
+        self.publish();
+    }
+
+    fn remove(&mut self) {
+        if let Some(card) = self.card.take() {
+            info!("Remove nfc card");
+            self.runtime.block_on(self.context.send_nfc_card_removed());
+            self.card = card.remove_card();
+            self.publish();
+        }
+    }
+
+    /// Expires a session the backend did not answer in time, drops a removed
+    /// card that was not picked up again and notices cards that silently left.
+    fn expire(&mut self) {
+        let card = match self.card.as_mut() {
+            Some(card) => card,
+            None => return,
+        };
+
+        if card.is_gone() {
+            self.remove();
+            return;
+        }
+
+        if card.has_timeout_occurred() {
+            self.card = None;
+        } else if card.auth_state_mut().expire_if_overdue(Instant::now()) {
+            self.runtime.block_on(self.context.send_error(
+                ErrorCode::Timeout,
+                "NFC Reader",
+                "NFC authentication timed out, re-authenticate the card!",
+            ));
+        } else {
+            return;
+        }
+        self.publish();
+    }
+
+    fn handle_command(&mut self, context: &ApplicationResponseContext, command: NfcCommand) {
+        let card = match self.card.take() {
+            Some(card) => card,
+            None => {
+                self.runtime.block_on(context.send_error(
+                    ErrorCode::NoCard,
+                    "NFC Reader",
+                    "No nfc card found!",
+                ));
+                return;
+            }
+        };
+
+        if card.has_timeout_occurred() {
+            // Card is no longer valid -> remove it.
+            self.publish();
+            self.runtime.block_on(context.send_error(
+                ErrorCode::Timeout,
+                "NFC Reader",
+                "NFC card timed out!",
+            ));
+            return;
+        }
+
+        let rejection = match (command.card_id(), command.auth_step()) {
+            (Some(card_id), _) if card.id() != Some(card_id) => Some((
+                ErrorCode::CardMismatch,
+                "The card on the reader does not match the card id!".to_owned(),
+            )),
+            (_, Some(step)) => card.auth_state().check(step, Instant::now()).err(),
+            _ => None,
+        };
+        if let Some((code, message)) = rejection {
+            self.card = Some(card);
+            self.runtime
+                .block_on(context.send_error(code, "NFC Reader", message));
+            return;
+        }
+
+        let card = self.runtime.block_on(async {
+            match command {
+                NfcCommand::Reauthenticate => handle_card_authentication(context, card).await,
+                NfcCommand::IdentifyResponse { card_id, card_type } => {
+                    handle_card_identify_response(context, card, card_id, card_type).await
+                }
+                NfcCommand::ChallengeResponse { card_id, challenge } => {
+                    handle_card_challenge_response(context, card, card_id, challenge).await
+                }
+                NfcCommand::ResponseResponse {
+                    card_id,
+                    session_key,
+                } => handle_card_response_response(context, card, card_id, session_key).await,
+                NfcCommand::Register { card_id } => {
+                    handle_card_register(context, card, card_id).await
+                }
+            }
+        });
+        self.card = Some(card);
+        self.publish();
+    }
+
+    /// Tells the router and the application about the current card.
+    fn publish(&self) {
+        if let Some(entry) = lock_readers(&self.readers).get_mut(&self.name) {
+            entry.has_card = self.card.is_some();
+            entry.card_id = self.card.as_ref().and_then(|card| card.get_id());
+        }
+
+        let status = self.card.as_ref().map(|card| card.status());
+        self.runtime
+            .block_on(self.context.send_nfc_card(&self.name, status));
+    }
+}
+
+#[test]
+pub fn select_reader_test() {
+    use crate::websocket_server::CardTypeDto;
+
+    let entry = |card_id: Option<u8>| ReaderEntry {
+        sender: mpsc::channel().0,
+        has_card: card_id.is_some(),
+        card_id: card_id.map(|id| vec![id]),
+    };
+    let identify = |id: u8| NfcCommand::IdentifyResponse {
+        card_id: vec![id],
+        card_type: CardTypeDto::GenericNfc,
+    };
+    let error = |result: Result<String, (ErrorCode, String)>| result.unwrap_err().0;
+
+    let mut readers = HashMap::new();
+    readers.insert("left".to_owned(), entry(Some(1)));
+    readers.insert("right".to_owned(), entry(Some(2)));
+    readers.insert("empty".to_owned(), entry(None));
+
+    assert_eq!(
+        select_reader(&readers, None, &identify(2)).unwrap(),
+        "right"
+    );
+    assert_eq!(
+        select_reader(&readers, Some("left"), &identify(1)).unwrap(),
+        "left"
+    );
+    assert_eq!(
+        error(select_reader(&readers, None, &identify(3))),
+        ErrorCode::CardMismatch
+    );
+    assert_eq!(
+        error(select_reader(&readers, Some("empty"), &identify(1))),
+        ErrorCode::NoCard
+    );
+    assert_eq!(
+        error(select_reader(&readers, None, &NfcCommand::Reauthenticate)),
+        ErrorCode::BadRequest
+    );
+
+    readers.remove("right");
+    assert_eq!(
+        select_reader(&readers, None, &NfcCommand::Reauthenticate).unwrap(),
+        "left"
+    );
+}
@@ -3,7 +3,7 @@ use log::info;
 use crate::{
     application::ApplicationResponseContext,
     nfc_module::{identify_atr, nfc::utils},
-    ServiceResult,
+    ErrorCode, ServiceResult,
 };
 
 use super::nfc::NfcCard;
@@ -15,6 +15,8 @@ pub struct UnsupportedCardHandler {
 }
 
 impl UnsupportedCardHandler {
+    pub const NAME: &'static str = "UnsupportedCard";
+
     pub fn check_combatibitility(atr: &[u8]) -> bool {
         true
     }
@@ -50,7 +52,11 @@ impl UnsupportedCardHandler {
         info!("    {}", utils::bytes_to_bytestring(&atr));
 
         context
-            .send_error("NFC Reader", "NFC Card type ist currently not supported!")
+            .send_error(
+                ErrorCode::UnsupportedCard,
+                "NFC Reader",
+                "NFC Card type ist currently not supported!",
+            )
             .await;
 
         Ok(())
@@ -3,7 +3,7 @@ use std::time::Duration;
 use log::{info, warn};
 use tokio::time;
 
-use crate::{application::ApplicationResponseContext, ServiceResult};
+use crate::{application::ApplicationResponseContext, supervisor::Shutdown, ServiceResult};
 
 pub struct QrModule {
     context: ApplicationResponseContext,
@@ -14,7 +14,7 @@ impl QrModule {
         Self { context }
     }
 
-    pub async fn run(mut self, useSimulation: bool) -> ServiceResult<()> {
+    pub async fn run(mut self, useSimulation: bool, mut shutdown: Shutdown) -> ServiceResult<()> {
         info!("Start qr module");
         if !cfg!(target_os = "linux") {
             if !useSimulation {
@@ -23,17 +23,37 @@ impl QrModule {
             warn!("Could not load libev. Fallback to stdin!");
         }
 
+        // Dropping `handle_reader` closes the scanner device.
         loop {
-            if let Err(e) = self.handle_reader().await {
-                warn!("Error while handling qr reader: {:?}", e);
+            tokio::select! {
+                result = self.handle_reader() => {
+                    if let Err(e) = result {
+                        warn!("Error while handling qr reader: {:?}", e);
+                    }
+                }
+                _ = shutdown.requested() => break,
+            }
+            tokio::select! {
+                _ = time::sleep(Duration::from_secs(1)) => {}
+                _ = shutdown.requested() => break,
             }
-            time::sleep(Duration::from_secs(1)).await;
         }
+
+        self.context.send_qr_scanner_state(false).await;
+        Ok(())
     }
 
     async fn handle_reader(&mut self) -> ServiceResult<()> {
         let mut reader = qr_reader::QrReader::new()?;
 
+        self.context.send_qr_scanner_state(true).await;
+        let result = self.read_codes(&mut reader).await;
+        self.context.send_qr_scanner_state(false).await;
+
+        result
+    }
+
+    async fn read_codes(&mut self, reader: &mut qr_reader::QrReader) -> ServiceResult<()> {
         while let Some(code) = reader.get_next_code().await? {
             self.context.send_barcode_identify_request(code).await;
         }
@@ -46,13 +66,16 @@ impl QrModule {
 mod qr_reader {
     extern crate evdev_rs;
 
-    use std::fs::File;
+    use std::fs::OpenOptions;
+    use std::io;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::{AsRawFd, RawFd};
 
     use evdev_rs::enums::{EventCode, EV_KEY};
     use evdev_rs::Device;
 
     use log::info;
-    use tokio::task;
+    use tokio::io::unix::AsyncFd;
 
     use crate::ServiceResult;
 
@@ -64,7 +87,11 @@ mod qr_reader {
 
     impl QrScanner {
         pub fn new(path: &str) -> ServiceResult<Self> {
-            let file = File::open(path)?;
+            // Non blocking, so a pending read never keeps the device open on shutdown.
+            let file = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(path)?;
             let device = Device::new_from_file(file)?;
 
             Ok(QrScanner {
@@ -74,16 +101,22 @@ mod qr_reader {
             })
         }
 
-        pub fn next_code(&mut self) -> ServiceResult<String> {
+        pub fn fd(&self) -> RawFd {
+            self.device.file().as_raw_fd()
+        }
+
+        /// Reads the pending events until a code is complete, `None` if there are no more.
+        pub fn try_next_code(&mut self) -> io::Result<Option<String>> {
             loop {
-                let event = self
-                    .device
-                    .next_event(evdev_rs::ReadFlag::NORMAL | evdev_rs::ReadFlag::BLOCKING)
-                    .map(|val| val.1)?;
+                let event = match self.device.next_event(evdev_rs::ReadFlag::NORMAL) {
+                    Ok((_, event)) => event,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                    Err(e) => return Err(e),
+                };
 
                 if let EventCode::EV_KEY(key) = event.event_code {
                     if let Some(code) = self.parse(event.value, key) {
-                        return Ok(code);
+                        return Ok(Some(code));
                     }
                 }
             }
@@ -172,6 +205,8 @@ mod qr_reader {
     }
 
     pub struct QrReader {
+        // Declared first, so it is deregistered before the scanner closes the device.
+        fd: AsyncFd<RawFd>,
         scanner: QrScanner,
     }
 
@@ -181,13 +216,18 @@ mod qr_reader {
             info!("Connect qr scanner {}", path);
 
             let scanner = QrScanner::new(&path)?;
-            Ok(Self { scanner })
+            let fd = AsyncFd::new(scanner.fd())?;
+            Ok(Self { fd, scanner })
         }
 
         pub async fn get_next_code(&mut self) -> ServiceResult<Option<String>> {
-            let code = task::block_in_place(|| self.scanner.next_code())?;
-
-            Ok(Some(code))
+            loop {
+                let mut guard = self.fd.readable().await?;
+                match self.scanner.try_next_code()? {
+                    Some(code) => return Ok(Some(code)),
+                    None => guard.clear_ready(),
+                }
+            }
         }
     }
 }
@@ -0,0 +1,290 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+use crate::{
+    application::ApplicationResponseContext,
+    config::env_seconds,
+    websocket_server::{ModuleHealthDto, ModuleStateDto},
+    ServiceError, ServiceResult,
+};
+
+const DEFAULT_INITIAL_BACKOFF: u64 = 1;
+const DEFAULT_MAX_BACKOFF: u64 = 60;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 5;
+
+/// Time the aborted modules get to drop their devices.
+const ABORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Reads how long the modules may take to shut down from `SHUTDOWN_TIMEOUT`
+/// in seconds, defaults to 5.
+pub fn shutdown_timeout_from_env() -> Duration {
+    env_seconds("SHUTDOWN_TIMEOUT", DEFAULT_SHUTDOWN_TIMEOUT)
+}
+
+/// Tells a module to finish its work and release its devices.
+#[derive(Clone)]
+pub struct Shutdown {
+    receiver: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    pub fn is_requested(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves once the shutdown is requested.
+    pub async fn requested(&mut self) {
+        while !*self.receiver.borrow_and_update() {
+            // Without a supervisor there is nobody left to wait for.
+            if self.receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Delay before restarting a failed module, doubled after every failure.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max: max.max(initial),
+            current: initial,
+        }
+    }
+
+    /// Reads `MODULE_RESTART_BACKOFF` and `MODULE_RESTART_BACKOFF_MAX` in seconds,
+    /// defaults to 1 and 60.
+    pub fn from_env() -> Self {
+        Self::new(
+            env_seconds("MODULE_RESTART_BACKOFF", DEFAULT_INITIAL_BACKOFF),
+            env_seconds("MODULE_RESTART_BACKOFF_MAX", DEFAULT_MAX_BACKOFF),
+        )
+    }
+
+    /// Returns the delay for a module that failed after running for `uptime`.
+    ///
+    /// A module that stayed up longer than the maximum delay counts as recovered
+    /// and starts over with the initial delay.
+    pub fn next(&mut self, uptime: Duration) -> Duration {
+        if uptime > self.max {
+            self.current = self.initial;
+        }
+
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+}
+
+/// Task of a module, aborted together with the task that supervises it.
+struct ModuleTask(JoinHandle<ServiceResult<()>>);
+
+impl Drop for ModuleTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs the modules of the terminal, restarts them when they fail and reports
+/// their health to the application.
+pub struct Supervisor {
+    context: ApplicationResponseContext,
+    shutdown: watch::Sender<bool>,
+    modules: Vec<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    pub fn new(context: ApplicationResponseContext) -> Self {
+        let (shutdown, _) = watch::channel(false);
+        Self {
+            context,
+            shutdown,
+            modules: Vec::new(),
+        }
+    }
+
+    /// Starts a module in its own task. `start` is called again for every restart.
+    ///
+    /// A module that returns `Ok` is considered finished and is not restarted.
+    pub fn spawn<F, Fut>(&mut self, module: &'static str, mut start: F)
+    where
+        F: FnMut(Shutdown) -> Fut + Send + 'static,
+        Fut: Future<Output = ServiceResult<()>> + Send + 'static,
+    {
+        let context = self.context.clone();
+        let mut backoff = Backoff::from_env();
+        let mut shutdown = Shutdown {
+            receiver: self.shutdown.subscribe(),
+        };
+
+        let handle = tokio::spawn(async move {
+            let mut restarts = 0;
+            let health = |state, restarts, error| ModuleHealthDto {
+                module: module.to_owned(),
+                state,
+                restarts,
+                error,
+            };
+
+            loop {
+                context
+                    .send_module_health(health(ModuleStateDto::Running, restarts, None))
+                    .await;
+
+                let started = Instant::now();
+                let mut task = ModuleTask(tokio::spawn(start(shutdown.clone())));
+                let error = match (&mut task.0).await {
+                    Ok(Ok(())) => {
+                        info!("Module {} stopped", module);
+                        context
+                            .send_module_health(health(ModuleStateDto::Stopped, restarts, None))
+                            .await;
+                        return;
+                    }
+                    Ok(Err(e)) => e,
+                    Err(e) => ServiceError::from(e),
+                };
+
+                if shutdown.is_requested() {
+                    error!("Module {} failed during shutdown: {}", module, error);
+                    context
+                        .send_module_health(health(
+                            ModuleStateDto::Stopped,
+                            restarts,
+                            Some(format!("{error}")),
+                        ))
+                        .await;
+                    return;
+                }
+
+                let delay = backoff.next(started.elapsed());
+                restarts += 1;
+                error!(
+                    "Module {} failed: {}. Restart in {}s",
+                    module,
+                    error,
+                    delay.as_secs_f32()
+                );
+                context
+                    .send_module_health(health(
+                        ModuleStateDto::Restarting,
+                        restarts,
+                        Some(format!("{error}")),
+                    ))
+                    .await;
+
+                tokio::select! {
+                    _ = time::sleep(delay) => {}
+                    _ = shutdown.requested() => return,
+                }
+            }
+        });
+        self.modules.push(handle);
+    }
+
+    /// Asks every module to stop and waits for them until the timeout, then
+    /// aborts the remaining ones.
+    ///
+    /// Returns whether all modules stopped on their own.
+    pub async fn shutdown(mut self, timeout: Duration) -> bool {
+        info!("Shutdown modules");
+        self.shutdown.send_replace(true);
+
+        let modules = futures::future::join_all(self.modules.iter_mut());
+        if time::timeout(timeout, modules).await.is_ok() {
+            return true;
+        }
+
+        warn!(
+            "Modules did not stop within {}s, abort them",
+            timeout.as_secs_f32()
+        );
+        for module in &self.modules {
+            module.abort();
+        }
+        let modules = futures::future::join_all(self.modules);
+        if time::timeout(ABORT_TIMEOUT, modules).await.is_err() {
+            error!("Modules did not stop after they were aborted");
+        }
+        false
+    }
+}
+
+#[test]
+pub fn backoff_test() {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+    let quick = Duration::from_millis(10);
+
+    assert_eq!(backoff.next(quick), Duration::from_secs(1));
+    assert_eq!(backoff.next(quick), Duration::from_secs(2));
+    assert_eq!(backoff.next(quick), Duration::from_secs(4));
+    assert_eq!(backoff.next(quick), Duration::from_secs(5));
+    assert_eq!(backoff.next(quick), Duration::from_secs(5));
+    assert_eq!(backoff.next(Duration::from_secs(6)), Duration::from_secs(1));
+}
+
+#[test]
+pub fn shutdown_test() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use crate::application::Application;
+
+    /// Sets the flag once the module is dropped.
+    struct Dropped(Arc<AtomicBool>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let application = Application::new(true);
+        let context = application.get_response_context();
+        tokio::spawn(application.run());
+        let timeout = Duration::from_millis(50);
+
+        let mut supervisor = Supervisor::new(context.clone());
+        supervisor.spawn("polite", |mut shutdown| async move {
+            shutdown.requested().await;
+            Ok(())
+        });
+        assert!(supervisor.shutdown(timeout).await);
+
+        // A module that ignores the shutdown is aborted and dropped.
+        let dropped = Arc::new(AtomicBool::new(false));
+        let mut supervisor = Supervisor::new(context);
+        let flag = dropped.clone();
+        supervisor.spawn("stubborn", move |_| {
+            let dropped = Dropped(flag.clone());
+            async move {
+                futures::future::pending::<()>().await;
+                drop(dropped);
+                Ok(())
+            }
+        });
+        assert!(!supervisor.shutdown(timeout).await);
+        for _ in 0..100 {
+            if dropped.load(Ordering::SeqCst) {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(dropped.load(Ordering::SeqCst));
+    });
+}
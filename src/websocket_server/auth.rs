@@ -0,0 +1,239 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use sha2::Sha256;
+use tokio_tungstenite::tungstenite::handshake::server::Request;
+
+use super::Role;
+
+/// Maximum age (and clock skew) of a HMAC signed timestamp in seconds.
+const HMAC_MAX_AGE: u64 = 60;
+
+enum Credential {
+    /// Pre-shared token, sent as `Authorization: Bearer <token>` header or `token` query parameter.
+    Token(String),
+    /// Shared secret for `timestamp` and `signature` query parameters, where
+    /// `signature` is the hex encoded HMAC-SHA256 of `timestamp`.
+    HmacSecret(Vec<u8>),
+}
+
+/// Checks the credentials of a websocket upgrade request.
+pub struct Authenticator {
+    credentials: Vec<(Role, Credential)>,
+    allowed_origins: Vec<String>,
+    /// Accept clients without valid credentials as kiosk.
+    disabled: bool,
+}
+
+impl Authenticator {
+    /// Reads the credentials of every role and the comma separated
+    /// `WEBSOCKET_ALLOWED_ORIGINS`.
+    ///
+    /// `WEBSOCKET_KIOSK_TOKEN`/`WEBSOCKET_KIOSK_HMAC_SECRET` grant the kiosk role,
+    /// `WEBSOCKET_ADMIN_TOKEN`/`WEBSOCKET_ADMIN_HMAC_SECRET` and the older
+    /// `WEBSOCKET_AUTH_TOKEN`/`WEBSOCKET_AUTH_HMAC_SECRET` grant the admin role.
+    /// Without any credential every client is rejected, unless `WEBSOCKET_AUTH=disabled`
+    /// accepts clients without valid credentials as kiosk.
+    pub fn from_env() -> Self {
+        let mut credentials = Vec::new();
+        for (role, prefix) in [
+            (Role::Admin, "WEBSOCKET_ADMIN"),
+            (Role::Admin, "WEBSOCKET_AUTH"),
+            (Role::Kiosk, "WEBSOCKET_KIOSK"),
+        ] {
+            if let Ok(token) = std::env::var(format!("{prefix}_TOKEN")) {
+                credentials.push((role, Credential::Token(token)));
+            }
+            if let Ok(secret) = std::env::var(format!("{prefix}_HMAC_SECRET")) {
+                credentials.push((role, Credential::HmacSecret(secret.into_bytes())));
+            }
+        }
+
+        let allowed_origins: Vec<String> = std::env::var("WEBSOCKET_ALLOWED_ORIGINS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|origin| origin.trim().to_owned())
+            .filter(|origin| !origin.is_empty())
+            .collect();
+
+        let disabled = match std::env::var("WEBSOCKET_AUTH") {
+            Ok(value) if value == "disabled" => true,
+            Ok(value) => {
+                warn!(
+                    "Unknown WEBSOCKET_AUTH '{}', authentication stays enabled!",
+                    value
+                );
+                false
+            }
+            Err(_) => false,
+        };
+
+        if disabled {
+            warn!("Websocket authentication disabled, clients without credentials are accepted as kiosk!");
+        } else if credentials.is_empty() {
+            warn!("No websocket credentials configured, every client is rejected!");
+        } else {
+            info!("Websocket clients have to authenticate");
+        }
+
+        Self {
+            credentials,
+            allowed_origins,
+            disabled,
+        }
+    }
+
+    /// Returns the role of the client or the reason for rejecting the request.
+    pub fn authenticate(&self, request: &Request) -> Result<Role, String> {
+        if !self.allowed_origins.is_empty() {
+            let origin = request
+                .headers()
+                .get("Origin")
+                .and_then(|origin| origin.to_str().ok());
+
+            match origin {
+                Some(origin) if self.allowed_origins.iter().any(|o| o == origin) => {}
+                Some(origin) => return Err(format!("Origin '{origin}' is not allowed")),
+                None => return Err("Missing origin".to_owned()),
+            }
+        }
+
+        let bearer = request
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let query = request.uri().query().unwrap_or("");
+        let token = bearer.or_else(|| query_parameter(query, "token"));
+        let timestamp = query_parameter(query, "timestamp");
+        let signature = query_parameter(query, "signature");
+
+        let role = self.credentials.iter().find_map(|(role, credential)| {
+            let valid = match credential {
+                Credential::Token(expected) => token
+                    .map(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+                    .unwrap_or(false),
+                Credential::HmacSecret(secret) => match (timestamp, signature) {
+                    (Some(timestamp), Some(signature)) => {
+                        verify_signature(secret, timestamp, signature, unix_time())
+                    }
+                    _ => false,
+                },
+            };
+            valid.then_some(*role)
+        });
+
+        match role {
+            Some(role) => Ok(role),
+            None if self.disabled => Ok(Role::Kiosk),
+            None => Err("Missing or invalid credentials".to_owned()),
+        }
+    }
+}
+
+fn query_parameter<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+fn verify_signature(secret: &[u8], timestamp: &str, signature: &str, now: u64) -> bool {
+    let time = match timestamp.parse::<u64>() {
+        Ok(time) => time,
+        Err(_) => return false,
+    };
+    if time.abs_diff(now) > HMAC_MAX_AGE {
+        return false;
+    }
+
+    let signature = match decode_hex(signature) {
+        Some(signature) => signature,
+        None => return false,
+    };
+
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(timestamp.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[test]
+pub fn hmac_signature_test() {
+    let secret = b"secret";
+    let timestamp = "1700000000";
+    let now = 1700000000;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    mac.update(timestamp.as_bytes());
+    let signature: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|x| format!("{x:02x}"))
+        .collect();
+
+    assert!(verify_signature(secret, timestamp, &signature, now + 30));
+    assert!(!verify_signature(secret, timestamp, &signature, now + 90));
+    assert!(!verify_signature(b"other", timestamp, &signature, now));
+    assert!(!verify_signature(secret, "1700000001", &signature, now));
+    assert!(!verify_signature(secret, timestamp, &signature[1..], now));
+}
+
+#[test]
+pub fn authenticate_test() {
+    use tokio_tungstenite::tungstenite::http;
+
+    let request = |uri: &str| http::Request::builder().uri(uri).body(()).unwrap();
+    let authenticator = |credentials, disabled| Authenticator {
+        credentials,
+        allowed_origins: Vec::new(),
+        disabled,
+    };
+
+    // Without credentials nobody gets in, unless authentication is disabled.
+    assert!(authenticator(Vec::new(), false)
+        .authenticate(&request("/"))
+        .is_err());
+    assert_eq!(
+        authenticator(Vec::new(), true).authenticate(&request("/")),
+        Ok(Role::Kiosk)
+    );
+
+    let admin = || vec![(Role::Admin, Credential::Token("secret".to_owned()))];
+    assert!(authenticator(admin(), false)
+        .authenticate(&request("/?token=wrong"))
+        .is_err());
+    assert_eq!(
+        authenticator(admin(), false).authenticate(&request("/?token=secret")),
+        Ok(Role::Admin)
+    );
+    assert_eq!(
+        authenticator(admin(), true).authenticate(&request("/?token=wrong")),
+        Ok(Role::Kiosk)
+    );
+}
@@ -0,0 +1,200 @@
+use std::io;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use log::info;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{ServiceError, ServiceResult};
+
+const DEFAULT_LISTEN_ADDRESS: &str = "0.0.0.0:9001";
+
+/// A configured websocket listener.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ListenAddress {
+    /// TCP socket address like `0.0.0.0:9001` or `[::1]:9001`.
+    Tcp { address: String, tls: bool },
+    /// Path of a Unix domain socket, always plain `ws://`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ListenAddress {
+    /// Parses a single entry of `WEBSOCKET_LISTEN`.
+    ///
+    /// `ws://<addr>` and `wss://<addr>` force plain or secure websockets, a bare
+    /// `<addr>` uses TLS if it is configured. `unix:<path>` binds a Unix domain socket.
+    fn parse(value: &str, tls_configured: bool) -> ServiceResult<Self> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(ListenAddress::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            return Err(ServiceError::InternalError(
+                "Listen configuration error",
+                format!("Unix domain sockets are not supported on this platform: '{path}'"),
+            ));
+        }
+
+        let (address, tls) = if let Some(address) = value.strip_prefix("wss://") {
+            if !tls_configured {
+                return Err(ServiceError::InternalError(
+                    "Listen configuration error",
+                    format!("'{value}' requires WEBSOCKET_TLS_CERT and WEBSOCKET_TLS_KEY!"),
+                ));
+            }
+            (address, true)
+        } else if let Some(address) = value.strip_prefix("ws://") {
+            (address, false)
+        } else {
+            (value, tls_configured)
+        };
+
+        Ok(ListenAddress::Tcp {
+            address: address.to_owned(),
+            tls,
+        })
+    }
+}
+
+/// Reads the comma separated listen addresses from `WEBSOCKET_LISTEN`,
+/// e.g. `127.0.0.1:9001,[::1]:9001,unix:/run/ascii-pay/terminal.sock`.
+///
+/// Defaults to `0.0.0.0:9001`.
+pub fn listen_addresses_from_env(tls_configured: bool) -> ServiceResult<Vec<ListenAddress>> {
+    let value = std::env::var("WEBSOCKET_LISTEN").unwrap_or_default();
+    parse_listen_addresses(&value, tls_configured)
+}
+
+fn parse_listen_addresses(value: &str, tls_configured: bool) -> ServiceResult<Vec<ListenAddress>> {
+    let addresses = value
+        .split(',')
+        .map(|address| address.trim())
+        .filter(|address| !address.is_empty())
+        .map(|address| ListenAddress::parse(address, tls_configured))
+        .collect::<ServiceResult<Vec<_>>>()?;
+
+    if addresses.is_empty() {
+        return Ok(vec![ListenAddress::Tcp {
+            address: DEFAULT_LISTEN_ADDRESS.to_owned(),
+            tls: tls_configured,
+        }]);
+    }
+
+    Ok(addresses)
+}
+
+/// Accepted connection before the websocket (and TLS) handshake.
+pub enum Connection {
+    Tcp(TcpStream, Option<TlsAcceptor>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+pub enum Listener {
+    Tcp(TcpListener, Option<TlsAcceptor>),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(
+        address: &ListenAddress,
+        acceptor: &Option<TlsAcceptor>,
+    ) -> ServiceResult<Self> {
+        match address {
+            ListenAddress::Tcp { address, tls } => {
+                let listener = TcpListener::bind(address).await?;
+                if *tls {
+                    info!(
+                        "Listen for secure websocket connections (wss://) on {}",
+                        address
+                    );
+                    Ok(Listener::Tcp(listener, acceptor.clone()))
+                } else {
+                    info!("Listen for websocket connections (ws://) on {}", address);
+                    Ok(Listener::Tcp(listener, None))
+                }
+            }
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)?;
+                info!(
+                    "Listen for websocket connections (ws://) on unix:{}",
+                    path.display()
+                );
+                Ok(Listener::Unix(listener))
+            }
+        }
+    }
+
+    /// Waits for the next connection and returns it with a printable peer address.
+    pub async fn accept(&self) -> io::Result<(Connection, String)> {
+        match self {
+            Listener::Tcp(listener, acceptor) => {
+                let (stream, peer) = listener.accept().await?;
+                Ok((Connection::Tcp(stream, acceptor.clone()), peer.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                let peer = match peer.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "unix".to_owned(),
+                };
+                Ok((Connection::Unix(stream), peer))
+            }
+        }
+    }
+}
+
+/// Removes a socket left behind by a previous run, but never a regular file.
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> ServiceResult<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            std::fs::remove_file(path)?;
+            Ok(())
+        }
+        Ok(_) => Err(ServiceError::InternalError(
+            "Listen configuration error",
+            format!("'{}' exists and is not a socket!", path.display()),
+        )),
+        Err(_) => Ok(()),
+    }
+}
+
+#[test]
+pub fn listen_address_test() {
+    assert_eq!(
+        parse_listen_addresses("", false).unwrap(),
+        vec![ListenAddress::Tcp {
+            address: "0.0.0.0:9001".to_owned(),
+            tls: false
+        }]
+    );
+    assert_eq!(
+        parse_listen_addresses(
+            "127.0.0.1:9001, ws://[::1]:9001,unix:/tmp/terminal.sock",
+            true
+        )
+        .unwrap(),
+        vec![
+            ListenAddress::Tcp {
+                address: "127.0.0.1:9001".to_owned(),
+                tls: true
+            },
+            ListenAddress::Tcp {
+                address: "[::1]:9001".to_owned(),
+                tls: false
+            },
+            ListenAddress::Unix(PathBuf::from("/tmp/terminal.sock")),
+        ]
+    );
+    assert!(parse_listen_addresses("wss://0.0.0.0:9001", false).is_err());
+}
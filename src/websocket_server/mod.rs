@@ -0,0 +1,679 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, Mutex},
+    time,
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{Request, Response},
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
+};
+
+use crate::{
+    application::ApplicationRequestContext, supervisor::Shutdown, ErrorCode, ServiceResult,
+};
+
+mod auth;
+use auth::Authenticator;
+mod listener;
+use listener::{Connection, Listener};
+mod peers;
+pub use peers::WebsocketMetrics;
+use peers::{Keepalive, Peer, PeerMap, Pinger, SlowClientPolicy};
+mod snapshot;
+use snapshot::Snapshot;
+mod tls;
+
+/// Protocol version spoken by this terminal.
+///
+/// Bump this whenever a message or payload changes incompatibly.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// All protocol versions this terminal can still serve, newest first.
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[u32] = &[PROTOCOL_VERSION];
+
+/// Time the connections get to flush their close frames when the server stops.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Pause of a listener after a failed accept, so a persistent error does not spin.
+pub const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Picks the newest protocol version supported by both sides.
+pub fn negotiate_protocol_version(client_versions: &[u32]) -> Option<u32> {
+    SUPPORTED_PROTOCOL_VERSIONS
+        .iter()
+        .copied()
+        .find(|v| client_versions.contains(v))
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub enum CardTypeDto {
+    GenericNfc,
+    AsciiMifare,
+    HostCardEmulation,
+}
+
+/// Permission level of a websocket client, bound to the credential it authenticated with.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Serialize, Deserialize)]
+pub enum Role {
+    /// May only complete the identify/challenge/response flow.
+    Kiosk,
+    /// May additionally register and re-authenticate cards.
+    Admin,
+}
+
+/// Progress of a card through the identify/challenge/response flow.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+pub enum AuthStageDto {
+    /// Card was read, the identify request is pending.
+    Detected,
+    /// Identify response received, the challenge request is pending.
+    Identified,
+    /// Challenge response received, the response request is pending.
+    Challenged,
+    Authenticated,
+    /// Card left the reader, but its authentication is kept for a few seconds.
+    Removed,
+    /// Backend did not answer in time, the card has to be re-authenticated.
+    Expired,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CardStatusDto {
+    pub card_id: Option<String>,
+    pub handler: Option<String>,
+    pub card_type: Option<CardTypeDto>,
+    pub stage: AuthStageDto,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReaderStatusDto {
+    pub name: String,
+    pub card: Option<CardStatusDto>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebsocketMetricsDto {
+    pub dropped_messages: u64,
+    pub disconnected_clients: u64,
+    pub evicted_clients: u64,
+}
+
+/// Lifecycle state of a supervised module.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+pub enum ModuleStateDto {
+    Running,
+    /// Module failed and is restarted after a backoff.
+    Restarting,
+    /// Module finished on its own, e.g. no qr scanner support on this platform.
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModuleHealthDto {
+    pub module: String,
+    pub state: ModuleStateDto,
+    pub restarts: u32,
+    /// Reason of the last failure.
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapabilitiesDto {
+    pub card_handlers: Vec<String>,
+    pub qr_scanner: bool,
+    pub simulation: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload")]
+pub enum WebsocketResponseMessage {
+    BarcodeIdentifyRequest {
+        barcode: String,
+    },
+
+    NfcIdentifyRequest {
+        card_id: String,
+        name: String,
+    },
+    NfcChallengeRequest {
+        card_id: String,
+        request: String,
+    },
+    NfcResponseRequest {
+        card_id: String,
+        challenge: String,
+        response: String,
+    },
+
+    NfcCardRemoved,
+    /// A nfc reader was plugged in, named by the envelope's `reader`.
+    ReaderConnected,
+    /// A nfc reader was unplugged, named by the envelope's `reader`.
+    ReaderDisconnected,
+    Readers {
+        readers: Vec<ReaderStatusDto>,
+    },
+    NfcRegisterRequest {
+        name: String,
+        card_id: String,
+        card_type: CardTypeDto,
+        data: Option<String>,
+    },
+
+    Welcome {
+        protocol_version: u32,
+        version: String,
+        build: String,
+        role: Role,
+        capabilities: CapabilitiesDto,
+    },
+    HelloRejected {
+        code: ErrorCode,
+        supported_versions: Vec<u32>,
+        message: String,
+    },
+    Status {
+        readers: Vec<ReaderStatusDto>,
+        qr_scanner: bool,
+        simulation: bool,
+        websocket: WebsocketMetricsDto,
+        modules: Vec<ModuleHealthDto>,
+    },
+    ModuleHealth(ModuleHealthDto),
+
+    Error {
+        code: ErrorCode,
+        source: String,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload")]
+#[allow(clippy::enum_variant_names)]
+pub enum WebsocketRequestMessage {
+    NfcIdentifyResponse {
+        card_id: String,
+        card_type: CardTypeDto,
+    },
+    NfcChallengeResponse {
+        card_id: String,
+        challenge: String,
+    },
+    NfcResponseResponse {
+        card_id: String,
+        session_key: String,
+    },
+
+    NfcRegister {
+        card_id: String,
+    },
+    NfcReauthenticate,
+
+    /// Optional handshake, clients that never send it are treated as protocol version 1.
+    Hello {
+        protocol_versions: Vec<u32>,
+    },
+    GetStatus,
+    GetReaders,
+}
+
+impl WebsocketRequestMessage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebsocketRequestMessage::NfcIdentifyResponse { .. } => "NfcIdentifyResponse",
+            WebsocketRequestMessage::NfcChallengeResponse { .. } => "NfcChallengeResponse",
+            WebsocketRequestMessage::NfcResponseResponse { .. } => "NfcResponseResponse",
+            WebsocketRequestMessage::NfcRegister { .. } => "NfcRegister",
+            WebsocketRequestMessage::NfcReauthenticate => "NfcReauthenticate",
+            WebsocketRequestMessage::Hello { .. } => "Hello",
+            WebsocketRequestMessage::GetStatus => "GetStatus",
+            WebsocketRequestMessage::GetReaders => "GetReaders",
+        }
+    }
+
+    /// Least privileged role that is allowed to send this request.
+    pub fn required_role(&self) -> Role {
+        match self {
+            WebsocketRequestMessage::Hello { .. }
+            | WebsocketRequestMessage::GetStatus
+            | WebsocketRequestMessage::GetReaders
+            | WebsocketRequestMessage::NfcIdentifyResponse { .. }
+            | WebsocketRequestMessage::NfcChallengeResponse { .. }
+            | WebsocketRequestMessage::NfcResponseResponse { .. }
+            | WebsocketRequestMessage::NfcReauthenticate => Role::Kiosk,
+            WebsocketRequestMessage::NfcRegister { .. } => Role::Admin,
+        }
+    }
+}
+
+/// Envelope of an incoming message.
+///
+/// The optional `request_id` is chosen by the client and echoed on every
+/// response or error caused by this request. Nfc requests may name the `reader`
+/// they are meant for, which is required as soon as several readers hold a card.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebsocketRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reader: Option<String>,
+    #[serde(flatten)]
+    pub message: WebsocketRequestMessage,
+}
+
+/// Envelope of an outgoing message, `request_id` is unset for events and
+/// `reader` names the nfc reader a message is about.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebsocketResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reader: Option<String>,
+    #[serde(flatten)]
+    pub message: WebsocketResponseMessage,
+}
+
+/// Identifies a single websocket connection for the lifetime of the process.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub struct SessionId(u64);
+
+impl std::fmt::Display for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// Addressee of an outgoing websocket message.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Recipient {
+    /// Events like card insertion or barcode scans, sent to every client.
+    Broadcast,
+    /// Replies to a request, sent only to the client that caused them.
+    Session(SessionId),
+}
+
+pub struct WebsocketServer {
+    context: ApplicationRequestContext,
+    map: PeerMap,
+    metrics: Arc<WebsocketMetrics>,
+}
+
+impl WebsocketServer {
+    pub fn new(context: ApplicationRequestContext, metrics: Arc<WebsocketMetrics>) -> Self {
+        Self {
+            context,
+            map: Arc::new(Mutex::new(HashMap::new())),
+            metrics,
+        }
+    }
+
+    pub async fn run(self, mut shutdown: Shutdown) -> ServiceResult<()> {
+        info!("Start websocket module");
+
+        let acceptor = tls::load_tls_acceptor().map_err(|e| {
+            error!("Cannot load TLS configuration: {}", e);
+            e
+        })?;
+        let addresses = listener::listen_addresses_from_env(acceptor.is_some()).map_err(|e| {
+            error!("Cannot load listen configuration: {}", e);
+            e
+        })?;
+
+        let mut listeners = Vec::with_capacity(addresses.len());
+        for address in &addresses {
+            listeners.push(Listener::bind(address, &acceptor).await?);
+        }
+
+        let policy = SlowClientPolicy::from_env();
+        let mut rx = self.context.attach_websocket().await?;
+        let map = self.map.clone();
+        let metrics = self.metrics.clone();
+        let snapshot = Arc::new(std::sync::Mutex::new(Snapshot::from_env()));
+        let broadcast_snapshot = snapshot.clone();
+        let mut broadcast = tokio::spawn(async move {
+            while let Some((recipient, response)) = rx.recv().await {
+                let msg = match serde_json::to_string(&response) {
+                    Ok(msg) => Message::Text(msg),
+                    Err(e) => {
+                        error!("Cannot serialize websocket message: {}", e);
+                        continue;
+                    }
+                };
+
+                // Never wait for a client here, a slow one would stall every other client
+                // and the application bus behind it.
+                let mut map = map.lock().await;
+
+                // Updated while holding the map, so new peers see either the
+                // snapshot or the message, but never both or none.
+                if let Ok(mut snapshot) = broadcast_snapshot.lock() {
+                    snapshot.observe(&recipient, &response, &msg);
+                }
+
+                match recipient {
+                    Recipient::Broadcast => {
+                        let sessions: Vec<SessionId> = map.keys().copied().collect();
+                        for session in sessions {
+                            peers::deliver(&mut map, session, msg.clone(), policy, &metrics);
+                        }
+                    }
+                    Recipient::Session(session) => {
+                        peers::deliver(&mut map, session, msg, policy, &metrics);
+                    }
+                }
+            }
+        });
+
+        let (connections, mut connections_closed) = mpsc::channel::<()>(1);
+        let server = Arc::new(ServerState {
+            peer_map: self.map,
+            context: self.context,
+            authenticator: Authenticator::from_env(),
+            next_session: AtomicU64::new(0),
+            queue_size: peers::queue_size_from_env(),
+            keepalive: Keepalive::from_env(),
+            metrics: self.metrics,
+            snapshot,
+            _connections: connections,
+        });
+
+        let accept_loops = listeners
+            .into_iter()
+            .map(|listener| accept_loop(server.clone(), listener));
+        let result = tokio::select! {
+            _ = futures::future::join_all(accept_loops) => Ok(()),
+            result = &mut broadcast => result.map_err(|e| e.into()),
+            _ = shutdown.requested() => {
+                info!("Close websocket connections");
+                Ok(())
+            }
+        };
+
+        // Close every connection of this server, a restarted one starts without clients.
+        broadcast.abort();
+        {
+            let mut peers = server.peer_map.lock().await;
+            if shutdown.is_requested() {
+                for peer in peers.values() {
+                    peer.close(CloseCode::Away, "Terminal is shutting down");
+                }
+            }
+            peers.clear();
+        }
+
+        // Every connection holds the server state, wait for them to send their close frames.
+        drop(server);
+        if time::timeout(CLOSE_TIMEOUT, connections_closed.recv())
+            .await
+            .is_err()
+        {
+            warn!("Websocket connections did not close in time");
+        }
+
+        result
+    }
+}
+
+/// State shared by all listeners and connections.
+struct ServerState {
+    peer_map: PeerMap,
+    context: ApplicationRequestContext,
+    authenticator: Authenticator,
+    next_session: AtomicU64,
+    queue_size: usize,
+    keepalive: Option<Keepalive>,
+    metrics: Arc<WebsocketMetrics>,
+    snapshot: Arc<std::sync::Mutex<Snapshot>>,
+    /// Closed once the last connection is gone.
+    _connections: mpsc::Sender<()>,
+}
+
+/// Accepts connections until the server stops. Failing to accept a connection,
+/// e.g. when running out of file descriptors, only pauses this listener.
+async fn accept_loop(server: Arc<ServerState>, listener: Listener) {
+    loop {
+        let (connection, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Cannot accept websocket connection: {}", e);
+                time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let session = SessionId(server.next_session.fetch_add(1, Ordering::Relaxed));
+
+        tokio::spawn(accept_connection(server.clone(), session, peer, connection));
+    }
+}
+
+async fn accept_connection(
+    server: Arc<ServerState>,
+    session: SessionId,
+    peer: String,
+    connection: Connection,
+) {
+    let result = match connection {
+        Connection::Tcp(stream, Some(acceptor)) => match acceptor.accept(stream).await {
+            Ok(stream) => handle_connection(&server, session, &peer, stream).await,
+            Err(e) => Err(e.into()),
+        },
+        Connection::Tcp(stream, None) => handle_connection(&server, session, &peer, stream).await,
+        #[cfg(unix)]
+        Connection::Unix(stream) => handle_connection(&server, session, &peer, stream).await,
+    };
+
+    if let Err(e) = result {
+        error!("Error processing connection {} ({}): {}", session, peer, e);
+    }
+
+    server.peer_map.lock().await.remove(&session);
+}
+
+#[allow(clippy::result_large_err)]
+async fn handle_connection<S>(
+    server: &ServerState,
+    session: SessionId,
+    peer: &str,
+    stream: S,
+) -> ServiceResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let ServerState {
+        peer_map,
+        context,
+        authenticator,
+        ..
+    } = server;
+
+    let mut authentication = Err(String::new());
+    let mut ws_stream = accept_hdr_async(stream, |request: &Request, response: Response| {
+        authentication = authenticator.authenticate(request);
+        Ok(response)
+    })
+    .await?;
+
+    // The upgrade is completed anyway, so browsers can see the close code.
+    let role = match authentication {
+        Ok(role) => role,
+        Err(reason) => {
+            warn!(
+                "Reject unauthenticated WebSocket connection {} ({}): {}",
+                session, peer, reason
+            );
+            ws_stream
+                .close(Some(CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: "Unauthorized".into(),
+                }))
+                .await?;
+
+            // Wait for the closing handshake, but never for an unresponsive client.
+            let _ = time::timeout(Duration::from_secs(1), async {
+                while let Some(Ok(_)) = ws_stream.next().await {}
+            })
+            .await;
+            return Ok(());
+        }
+    };
+
+    let (tx, mut rx) = mpsc::channel(server.queue_size);
+    let (peer_entry, mut evicted) = Peer::new(tx.clone());
+    {
+        let mut peer_map = peer_map.lock().await;
+        if let Ok(snapshot) = server.snapshot.lock() {
+            for msg in snapshot.replay() {
+                let _ = tx.try_send(msg);
+            }
+        }
+        peer_map.insert(session, peer_entry);
+    }
+
+    info!(
+        "New WebSocket connection {} with role {:?}: {}",
+        session, role, peer
+    );
+
+    let (mut a, mut b) = ws_stream.split();
+
+    let mut writer = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if let Err(e) = a.send(msg).await {
+                error!("Cannot send websocket message: {}", e);
+            }
+        }
+        let _ = a.close().await;
+    });
+
+    let mut pinger = Pinger::new(server.keepalive);
+
+    let result: ServiceResult<()> = async {
+        loop {
+            let msg = tokio::select! {
+                msg = b.next() => match msg {
+                    Some(msg) => msg?,
+                    None => break,
+                },
+                // The peer was removed from the map, e.g. because it is too slow.
+                _ = &mut evicted => break,
+                silence = pinger.unresponsive(&tx) => {
+                    warn!(
+                        "Evict unresponsive WebSocket connection {} ({}), no message for {:?}",
+                        session, peer, silence
+                    );
+                    server.metrics.record_eviction();
+                    break;
+                }
+            };
+            pinger.seen();
+
+            let msg_data = match msg {
+                Message::Text(_) | Message::Binary(_) => msg.into_data(),
+                _ => continue,
+            };
+
+            if msg_data.is_empty() {
+                continue;
+            }
+
+            let request = serde_json::from_slice::<WebsocketRequest>(&msg_data);
+            match request {
+                Ok(WebsocketRequest {
+                    request_id,
+                    message: WebsocketRequestMessage::Hello { protocol_versions },
+                    ..
+                }) if negotiate_protocol_version(&protocol_versions).is_none() => {
+                    warn!(
+                        "Reject WebSocket connection {} ({}) with protocol versions {:?}",
+                        session, peer, protocol_versions
+                    );
+                    reject_connection(&tx, request_id, &protocol_versions).await?;
+                    break;
+                }
+                Ok(request) => context.send_websocket_request(session, role, request).await,
+                Err(e) => {
+                    error!("{}", e);
+                    context
+                        .error(
+                            session,
+                            parse_request_id(&msg_data),
+                            ErrorCode::ParseError,
+                            "WebSocket",
+                            "Could not parse WebSocket message!",
+                        )
+                        .await;
+                }
+            }
+        }
+
+        Ok(())
+    }
+    .await;
+
+    peer_map.lock().await.remove(&session);
+    drop(tx);
+
+    // Flush queued messages like a close frame, but never wait for an unresponsive client.
+    if time::timeout(Duration::from_secs(1), &mut writer)
+        .await
+        .is_err()
+    {
+        writer.abort();
+    }
+
+    result
+}
+
+async fn reject_connection(
+    sender: &mpsc::Sender<Message>,
+    request_id: Option<String>,
+    protocol_versions: &[u32],
+) -> ServiceResult<()> {
+    let message = format!(
+        "Unsupported protocol versions {protocol_versions:?}, this terminal speaks {SUPPORTED_PROTOCOL_VERSIONS:?}."
+    );
+    let response = WebsocketResponse {
+        request_id,
+        reader: None,
+        message: WebsocketResponseMessage::HelloRejected {
+            code: ErrorCode::UnsupportedProtocolVersion,
+            supported_versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+            message,
+        },
+    };
+
+    sender
+        .send(Message::Text(serde_json::to_string(&response)?))
+        .await?;
+    sender
+        .send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Protocol,
+            reason: "Unsupported protocol version".into(),
+        })))
+        .await?;
+
+    Ok(())
+}
+
+/// Best effort extraction of the `request_id` from a message that could not be parsed.
+fn parse_request_id(data: &[u8]) -> Option<String> {
+    let value = serde_json::from_slice::<serde_json::Value>(data).ok()?;
+    value.get("request_id")?.as_str().map(|id| id.to_owned())
+}
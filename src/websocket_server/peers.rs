@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::warn;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time;
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{SessionId, WebsocketMetricsDto};
+use crate::config::env_seconds;
+
+const DEFAULT_QUEUE_SIZE: usize = 16;
+const DEFAULT_PING_INTERVAL: u64 = 15;
+const DEFAULT_PING_TIMEOUT: u64 = 45;
+
+/// What to do with a client whose outgoing queue is full.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum SlowClientPolicy {
+    /// Drop the message for this client only.
+    Drop,
+    /// Drop the message and close the connection.
+    Disconnect,
+}
+
+impl SlowClientPolicy {
+    /// Reads `WEBSOCKET_SLOW_CLIENT_POLICY` (`drop` or `disconnect`), defaults to `drop`.
+    pub fn from_env() -> Self {
+        match std::env::var("WEBSOCKET_SLOW_CLIENT_POLICY").as_deref() {
+            Ok("disconnect") => SlowClientPolicy::Disconnect,
+            Ok("drop") | Err(_) => SlowClientPolicy::Drop,
+            Ok(value) => {
+                warn!(
+                    "Unknown WEBSOCKET_SLOW_CLIENT_POLICY '{}', drop messages instead",
+                    value
+                );
+                SlowClientPolicy::Drop
+            }
+        }
+    }
+}
+
+/// Reads the number of outgoing messages buffered per client from
+/// `WEBSOCKET_CLIENT_QUEUE_SIZE`, defaults to 16.
+pub fn queue_size_from_env() -> usize {
+    std::env::var("WEBSOCKET_CLIENT_QUEUE_SIZE")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|size| *size > 0)
+        .unwrap_or(DEFAULT_QUEUE_SIZE)
+}
+
+/// Server side ping/pong to detect clients that vanished without a close frame.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Keepalive {
+    /// Time between two pings.
+    pub interval: Duration,
+    /// Time without any message from the client after which it is evicted.
+    pub timeout: Duration,
+}
+
+impl Keepalive {
+    /// Reads `WEBSOCKET_PING_INTERVAL` and `WEBSOCKET_PING_TIMEOUT` in seconds,
+    /// defaults to 15 and 45. An interval of 0 disables the keepalive.
+    pub fn from_env() -> Option<Self> {
+        let keepalive = Self::new(
+            env_seconds("WEBSOCKET_PING_INTERVAL", DEFAULT_PING_INTERVAL),
+            env_seconds("WEBSOCKET_PING_TIMEOUT", DEFAULT_PING_TIMEOUT),
+        );
+        if keepalive.is_none() {
+            warn!("Websocket keepalive is disabled");
+        }
+        keepalive
+    }
+
+    /// An interval of 0 disables the keepalive, the timeout lasts at least one interval.
+    pub fn new(interval: Duration, timeout: Duration) -> Option<Self> {
+        if interval.is_zero() {
+            return None;
+        }
+
+        Some(Self {
+            interval,
+            timeout: timeout.max(interval),
+        })
+    }
+}
+
+/// Pings a single client and detects when it stopped answering.
+pub struct Pinger {
+    keepalive: Option<Keepalive>,
+    ticks: time::Interval,
+    last_seen: time::Instant,
+}
+
+impl Pinger {
+    pub fn new(keepalive: Option<Keepalive>) -> Self {
+        let interval = keepalive
+            .map(|k| k.interval)
+            .unwrap_or(Duration::from_secs(60));
+        let mut ticks = time::interval_at(time::Instant::now() + interval, interval);
+        ticks.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+        Self {
+            keepalive,
+            ticks,
+            last_seen: time::Instant::now(),
+        }
+    }
+
+    /// Records a message of the client, any message proves it is still there.
+    pub fn seen(&mut self) {
+        self.last_seen = time::Instant::now();
+    }
+
+    /// Queues a ping every interval until the client was silent for longer than
+    /// the timeout, then resolves to the time of silence. Never resolves if the
+    /// keepalive is disabled. Cancel safe.
+    pub async fn unresponsive(&mut self, sender: &mpsc::Sender<Message>) -> Duration {
+        let keepalive = match self.keepalive {
+            Some(keepalive) => keepalive,
+            None => return std::future::pending().await,
+        };
+
+        loop {
+            self.ticks.tick().await;
+
+            let silence = self.last_seen.elapsed();
+            if silence > keepalive.timeout {
+                return silence;
+            }
+
+            // A full queue means the client is slow, it is handled by the broadcast.
+            let _ = sender.try_send(Message::Ping(Vec::new()));
+        }
+    }
+}
+
+/// Counters of the websocket server, shared with the application for status reports.
+#[derive(Debug, Default)]
+pub struct WebsocketMetrics {
+    dropped_messages: AtomicU64,
+    disconnected_clients: AtomicU64,
+    evicted_clients: AtomicU64,
+}
+
+impl WebsocketMetrics {
+    pub fn snapshot(&self) -> WebsocketMetricsDto {
+        WebsocketMetricsDto {
+            dropped_messages: self.dropped_messages.load(Ordering::Relaxed),
+            disconnected_clients: self.disconnected_clients.load(Ordering::Relaxed),
+            evicted_clients: self.evicted_clients.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn record_eviction(&self) {
+        self.evicted_clients.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Outgoing side of a websocket connection.
+pub struct Peer {
+    sender: mpsc::Sender<Message>,
+    /// Dropping the peer resolves the matching receiver, which ends the connection.
+    _evict: oneshot::Sender<()>,
+}
+
+impl Peer {
+    pub fn new(sender: mpsc::Sender<Message>) -> (Self, oneshot::Receiver<()>) {
+        let (evict, evicted) = oneshot::channel();
+        (
+            Self {
+                sender,
+                _evict: evict,
+            },
+            evicted,
+        )
+    }
+
+    /// Queues a close frame, it is sent before the connection ends.
+    pub fn close(&self, code: CloseCode, reason: &'static str) {
+        let frame = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+        if self.sender.try_send(Message::Close(Some(frame))).is_err() {
+            warn!("Cannot queue close frame, the connection is closed without it");
+        }
+    }
+}
+
+pub type PeerMap = Arc<Mutex<HashMap<SessionId, Peer>>>;
+
+/// Queues a message for a client without waiting for it.
+pub fn deliver(
+    peers: &mut HashMap<SessionId, Peer>,
+    session: SessionId,
+    message: Message,
+    policy: SlowClientPolicy,
+    metrics: &WebsocketMetrics,
+) {
+    let peer = match peers.get(&session) {
+        Some(peer) => peer,
+        None => {
+            warn!("Drop websocket message for closed session {}", session);
+            return;
+        }
+    };
+
+    match peer.sender.try_send(message) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            let dropped = metrics.dropped_messages.fetch_add(1, Ordering::Relaxed) + 1;
+            match policy {
+                SlowClientPolicy::Drop => {
+                    warn!(
+                        "Websocket client {} is too slow, drop message ({} dropped in total)",
+                        session, dropped
+                    );
+                }
+                SlowClientPolicy::Disconnect => {
+                    warn!("Websocket client {} is too slow, disconnect", session);
+                    metrics.disconnected_clients.fetch_add(1, Ordering::Relaxed);
+                    peers.remove(&session);
+                }
+            }
+        }
+        Err(TrySendError::Closed(_)) => {
+            peers.remove(&session);
+        }
+    }
+}
+
+#[test]
+pub fn slow_client_test() {
+    let metrics = WebsocketMetrics::default();
+    let mut peers = HashMap::new();
+    let (sender, _receiver) = mpsc::channel(1);
+    let (peer, mut evicted) = Peer::new(sender);
+    peers.insert(SessionId(0), peer);
+
+    let message = || Message::Text("{}".to_owned());
+    deliver(
+        &mut peers,
+        SessionId(0),
+        message(),
+        SlowClientPolicy::Drop,
+        &metrics,
+    );
+    deliver(
+        &mut peers,
+        SessionId(0),
+        message(),
+        SlowClientPolicy::Drop,
+        &metrics,
+    );
+    assert_eq!(metrics.snapshot().dropped_messages, 1);
+    assert!(peers.contains_key(&SessionId(0)));
+
+    deliver(
+        &mut peers,
+        SessionId(0),
+        message(),
+        SlowClientPolicy::Disconnect,
+        &metrics,
+    );
+    assert_eq!(metrics.snapshot().dropped_messages, 2);
+    assert_eq!(metrics.snapshot().disconnected_clients, 1);
+    assert!(peers.is_empty());
+    assert!(matches!(
+        evicted.try_recv(),
+        Err(oneshot::error::TryRecvError::Closed)
+    ));
+}
+
+#[test]
+pub fn keepalive_test() {
+    let second = Duration::from_secs(1);
+    assert_eq!(Keepalive::new(Duration::ZERO, 45 * second), None);
+    assert_eq!(
+        Keepalive::new(15 * second, 5 * second).map(|k| k.timeout),
+        Some(15 * second)
+    );
+
+    // The clock is paused and only advances while every task waits, so the
+    // timings below are exact.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let interval = 20 * second;
+        let timeout = 100 * second;
+        let (sender, mut receiver) = mpsc::channel(16);
+
+        // A disabled keepalive neither pings nor evicts.
+        let mut pinger = Pinger::new(None);
+        assert!(time::timeout(timeout * 2, pinger.unresponsive(&sender))
+            .await
+            .is_err());
+        assert!(receiver.try_recv().is_err());
+
+        // A client that keeps talking is pinged every interval, but never evicted.
+        let start = time::Instant::now();
+        let mut pinger = Pinger::new(Keepalive::new(interval, timeout));
+        for _ in 0..5 {
+            pinger.seen();
+            assert!(time::timeout(timeout / 2, pinger.unresponsive(&sender))
+                .await
+                .is_err());
+        }
+        assert_eq!(start.elapsed(), 250 * second);
+        let mut pings = 0;
+        while let Ok(message) = receiver.try_recv() {
+            assert_eq!(message, Message::Ping(Vec::new()));
+            pings += 1;
+        }
+        assert_eq!(pings, 12);
+
+        // A silent client is evicted at the first tick after the timeout passed.
+        let silence = pinger.unresponsive(&sender).await;
+        assert_eq!(silence, 120 * second);
+        assert_eq!(start.elapsed(), 320 * second);
+    });
+}
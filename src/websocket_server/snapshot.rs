@@ -0,0 +1,171 @@
+use std::time::{Duration, Instant};
+
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{Recipient, WebsocketResponse, WebsocketResponseMessage};
+use crate::config::env_seconds;
+use crate::errors::ErrorCode;
+
+const DEFAULT_BARCODE_WINDOW: u64 = 10;
+
+/// Latest broadcast state, replayed to clients that connect afterwards.
+pub struct Snapshot {
+    /// Pending identify requests of the cards on the readers, by reader.
+    identify_requests: Vec<(Option<String>, Message)>,
+    barcode: Option<(Instant, Message)>,
+    barcode_window: Duration,
+}
+
+impl Snapshot {
+    /// Reads how long a scanned barcode is replayed from
+    /// `WEBSOCKET_REPLAY_BARCODE_WINDOW` in seconds, defaults to 10.
+    pub fn from_env() -> Self {
+        Self {
+            identify_requests: Vec::new(),
+            barcode: None,
+            barcode_window: env_seconds("WEBSOCKET_REPLAY_BARCODE_WINDOW", DEFAULT_BARCODE_WINDOW),
+        }
+    }
+
+    /// Updates the snapshot with an outgoing message and its serialized form.
+    ///
+    /// Messages to a single session are observed as well, as they tell when the
+    /// authentication of a card moved on and its identify request is stale. They
+    /// are never replayed, they carry the request id of another client.
+    pub fn observe(
+        &mut self,
+        recipient: &Recipient,
+        response: &WebsocketResponse,
+        serialized: &Message,
+    ) {
+        let reader = &response.reader;
+        match response.message {
+            WebsocketResponseMessage::NfcIdentifyRequest { .. } => {
+                self.identify_requests.retain(|(r, _)| r != reader);
+                if *recipient == Recipient::Broadcast {
+                    self.identify_requests
+                        .push((reader.clone(), serialized.clone()));
+                }
+            }
+            WebsocketResponseMessage::NfcChallengeRequest { .. }
+            | WebsocketResponseMessage::NfcResponseRequest { .. }
+            | WebsocketResponseMessage::NfcCardRemoved
+            | WebsocketResponseMessage::Error {
+                code: ErrorCode::Timeout,
+                ..
+            } => {
+                // A message without reader stems from a single reader terminal.
+                self.identify_requests
+                    .retain(|(r, _)| reader.is_some() && r != reader);
+            }
+            WebsocketResponseMessage::BarcodeIdentifyRequest { .. }
+                if *recipient == Recipient::Broadcast =>
+            {
+                self.barcode = Some((Instant::now(), serialized.clone()));
+            }
+            _ => {}
+        }
+    }
+
+    /// Messages a new client has missed, oldest first.
+    pub fn replay(&self) -> Vec<Message> {
+        let mut messages: Vec<Message> = self
+            .identify_requests
+            .iter()
+            .map(|(_, message)| message.clone())
+            .collect();
+
+        if let Some((time, message)) = &self.barcode {
+            if time.elapsed() <= self.barcode_window {
+                messages.push(message.clone());
+            }
+        }
+
+        messages
+    }
+}
+
+#[test]
+pub fn snapshot_test() {
+    use super::SessionId;
+
+    let mut snapshot = Snapshot::from_env();
+    let send = |snapshot: &mut Snapshot,
+                recipient: Recipient,
+                reader: Option<&str>,
+                message: WebsocketResponseMessage| {
+        let response = WebsocketResponse {
+            request_id: None,
+            reader: reader.map(str::to_owned),
+            message,
+        };
+        let serialized = Message::Text(serde_json::to_string(&response).unwrap());
+        snapshot.observe(&recipient, &response, &serialized);
+        serialized
+    };
+    let observe = |snapshot: &mut Snapshot, reader: Option<&str>, message| {
+        send(snapshot, Recipient::Broadcast, reader, message)
+    };
+    let identify = |card_id: &str| WebsocketResponseMessage::NfcIdentifyRequest {
+        card_id: card_id.to_owned(),
+        name: "Mifare DESFire".to_owned(),
+    };
+
+    let first = observe(&mut snapshot, Some("reader 1"), identify("AAAA"));
+    let second = observe(&mut snapshot, Some("reader 2"), identify("BBBB"));
+    let barcode = observe(
+        &mut snapshot,
+        None,
+        WebsocketResponseMessage::BarcodeIdentifyRequest {
+            barcode: "4006381333931".to_owned(),
+        },
+    );
+    observe(
+        &mut snapshot,
+        Some("reader 1"),
+        WebsocketResponseMessage::Readers { readers: vec![] },
+    );
+    assert_eq!(
+        snapshot.replay(),
+        vec![first, second.clone(), barcode.clone()]
+    );
+
+    // Once the authentication moved on, the identify request is stale
+    observe(
+        &mut snapshot,
+        Some("reader 1"),
+        WebsocketResponseMessage::NfcChallengeRequest {
+            card_id: "AAAA".to_owned(),
+            request: "AAAA".to_owned(),
+        },
+    );
+    assert_eq!(snapshot.replay(), vec![second, barcode.clone()]);
+
+    let third = observe(&mut snapshot, Some("reader 1"), identify("CCCC"));
+    observe(
+        &mut snapshot,
+        Some("reader 2"),
+        WebsocketResponseMessage::Error {
+            code: ErrorCode::Timeout,
+            source: "NFC Reader".to_owned(),
+            message: "NFC authentication timed out, re-authenticate the card!".to_owned(),
+        },
+    );
+    assert_eq!(snapshot.replay(), vec![third.clone(), barcode.clone()]);
+
+    // The identify request of a re-authentication belongs to the requesting session only
+    send(
+        &mut snapshot,
+        Recipient::Session(SessionId(7)),
+        Some("reader 2"),
+        identify("BBBB"),
+    );
+    assert_eq!(snapshot.replay(), vec![third, barcode.clone()]);
+
+    observe(
+        &mut snapshot,
+        None,
+        WebsocketResponseMessage::NfcCardRemoved,
+    );
+    assert_eq!(snapshot.replay(), vec![barcode]);
+}
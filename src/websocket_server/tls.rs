@@ -0,0 +1,74 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+use log::info;
+use rustls_pemfile::Item;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{ServiceError, ServiceResult};
+
+/// Builds the TLS acceptor for `wss://` connections.
+///
+/// TLS is enabled by setting both `WEBSOCKET_TLS_CERT` and `WEBSOCKET_TLS_KEY`
+/// to PEM files. Returns `None` if neither is set, so the server falls back to
+/// plain TCP.
+pub fn load_tls_acceptor() -> ServiceResult<Option<TlsAcceptor>> {
+    let cert_path = std::env::var("WEBSOCKET_TLS_CERT").ok();
+    let key_path = std::env::var("WEBSOCKET_TLS_KEY").ok();
+
+    let (cert_path, key_path) = match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        (None, None) => return Ok(None),
+        _ => {
+            return Err(ServiceError::InternalError(
+                "TLS configuration error",
+                "Both WEBSOCKET_TLS_CERT and WEBSOCKET_TLS_KEY have to be set!".to_owned(),
+            ))
+        }
+    };
+
+    info!("Load TLS certificate {}", cert_path);
+    let certs = load_certs(&cert_path)?;
+    let key = load_private_key(&key_path)?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+fn load_certs(path: &str) -> ServiceResult<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+
+    if certs.is_empty() {
+        return Err(ServiceError::InternalError(
+            "TLS configuration error",
+            format!("No certificate found in '{path}'!"),
+        ));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &str) -> ServiceResult<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key));
+            }
+            _ => {}
+        }
+    }
+
+    Err(ServiceError::InternalError(
+        "TLS configuration error",
+        format!("No private key found in '{path}'!"),
+    ))
+}